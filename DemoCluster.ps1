@@ -0,0 +1,14 @@
+cargo build --release
+
+$env:RUST_LOG="server,client"
+$Target = Join-Path $PSScriptRoot "target/release"
+
+Start-Process cmd -ArgumentList "/c $Target/server.exe --endpoint [::1]:10000 --cluster-endpoint [::1]:11000 --peer http://[::1]:11001 --peer http://[::1]:11002"
+Start-Process cmd -ArgumentList "/c $Target/server.exe --endpoint [::1]:10001 --cluster-endpoint [::1]:11001 --peer http://[::1]:11000 --peer http://[::1]:11002"
+Start-Process cmd -ArgumentList "/c $Target/server.exe --endpoint [::1]:10002 --cluster-endpoint [::1]:11002 --peer http://[::1]:11000 --peer http://[::1]:11001"
+
+Start-Sleep -Seconds 1
+
+Start-Process cmd -ArgumentList "/c $Target/client.exe --client-name client-1 --initial-key 0/0 --tui --endpoint http://[::1]:10000"
+Start-Process cmd -ArgumentList "/c $Target/client.exe --client-name client-2 --initial-key 8/0 --tui --endpoint http://[::1]:10001"
+Start-Process cmd -ArgumentList "/c $Target/client.exe --client-name client-3 --initial-key 16/0 --tui --endpoint http://[::1]:10002"
@@ -0,0 +1,15 @@
+cargo build --release
+
+$env:RUST_LOG="server,client"
+$Target = Join-Path $PSScriptRoot "target/release"
+$Nodes = "--node http://[::1]:10000 --node http://[::1]:10001 --node http://[::1]:10002"
+
+Start-Process cmd -ArgumentList "/c $Target/server.exe --endpoint [::1]:10000 $Nodes"
+Start-Process cmd -ArgumentList "/c $Target/server.exe --endpoint [::1]:10001 $Nodes"
+Start-Process cmd -ArgumentList "/c $Target/server.exe --endpoint [::1]:10002 $Nodes"
+
+Start-Sleep -Seconds 0.1
+
+Start-Process cmd -ArgumentList "/c $Target/client.exe --client-name client-1 --initial-key 0/0 --tui $Nodes"
+Start-Process cmd -ArgumentList "/c $Target/client.exe --client-name client-2 --initial-key 8/0 --tui $Nodes"
+Start-Process cmd -ArgumentList "/c $Target/client.exe --client-name client-3 --initial-key 16/0 --tui $Nodes"
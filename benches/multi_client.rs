@@ -11,33 +11,47 @@ use tokio::runtime::Runtime;
 pub const SHARD_COUNT: u32 = 8;
 pub const CONCURRENT_CLIENTS: u32 = 4;
 pub const ITERATIONS: u32 = 128;
+/// The simulated latency of the server in milliseconds, so that the cost of each handoff
+/// shows.
+pub const LATENCY: u32 = 2;
 
+/// Runs the same clients against a server which pipelines handoffs, with the clients
+/// sending releases along with their next acquire, and against one which does neither.
 pub fn multi_client_benchmark(c: &mut Criterion) {
-    let mut server = Command::new(bin_path("server").unwrap())
-        .arg("--shard-count")
-        .arg(format!("{}", SHARD_COUNT))
-        .arg("--latency")
-        .arg("0")
-        .spawn()
-        .unwrap();
-    // Wait for server to spin up
-    thread::sleep(Duration::from_secs(1));
-
     let rt = Runtime::new().unwrap();
-    c.bench_function("multi_client", |b| {
-        b.iter(|| {
-            rt.block_on(future::try_join_all(
-                (0..CONCURRENT_CLIENTS).map(run_client),
-            ))
-            .unwrap()
-        })
-    });
+    let mut group = c.benchmark_group("multi_client");
+    for &(name, pipelined) in &[("pipelined", true), ("serial", false)] {
+        let mut command = Command::new(bin_path("server").unwrap());
+        command
+            .arg("--shard-count")
+            .arg(format!("{}", SHARD_COUNT))
+            .arg("--latency")
+            .arg(format!("{}", LATENCY));
+        if !pipelined {
+            command.arg("--serial-handoffs");
+        }
+        let mut server = command.spawn().unwrap();
+        // Wait for server to spin up
+        thread::sleep(Duration::from_secs(1));
 
-    server.kill().unwrap();
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                rt.block_on(future::try_join_all(
+                    (0..CONCURRENT_CLIENTS).map(|id| run_client(id, pipelined)),
+                ))
+                .unwrap()
+            })
+        });
+
+        server.kill().unwrap();
+        server.wait().unwrap();
+    }
+    group.finish();
 }
 
-async fn run_client(id: u32) -> Result<(), Box<dyn std::error::Error>> {
-    let status = AsyncCommand::new(bin_path("client").unwrap())
+async fn run_client(id: u32, pipelined: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let mut command = AsyncCommand::new(bin_path("client").unwrap());
+    command
         .arg("--shard-count")
         .arg(format!("{}", SHARD_COUNT))
         .arg("--client-name")
@@ -47,9 +61,11 @@ async fn run_client(id: u32) -> Result<(), Box<dyn std::error::Error>> {
         .arg("--iterations")
         .arg(format!("{}", ITERATIONS))
         .arg("--access-duration")
-        .arg("0")
-        .status()
-        .await?;
+        .arg("0");
+    if !pipelined {
+        command.arg("--no-piggyback-releases");
+    }
+    let status = command.status().await?;
     if !status.success() {
         return Err(format!("client exited unsuccessfully ({})", status).into());
     }
@@ -0,0 +1,202 @@
+use std::collections::BTreeMap;
+
+use structopt::StructOpt;
+use tonic::Request;
+
+use shardik::api::*;
+
+#[derive(StructOpt)]
+struct Opts {
+    /// The service endpoint to use.
+    #[structopt(long, default_value = "http://[::1]:10000")]
+    endpoint: http::Uri,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Shows who holds each shard and who is waiting for it.
+    Queues {
+        /// The shards to show. If not set, every shard served by the server is shown.
+        shard_ids: Vec<String>,
+    },
+    /// Removes a client's pending acquire requests from a shard's queue.
+    Cancel { shard_id: String, client_id: String },
+    /// Shows counters describing how shards have been handed between clients.
+    Stats,
+    /// Lists the shards served by the server.
+    ListShards {
+        /// Whether to show the keys in each shard, rather than just how many there are.
+        #[structopt(long)]
+        keys: bool,
+    },
+    /// Creates a new shard with the given keys.
+    CreateShard { shard_id: String, keys: Vec<String> },
+    /// Deletes a shard, which must not have any locked keys.
+    DeleteShard { shard_id: String },
+    /// Adds keys to a shard.
+    AddKeys { shard_id: String, keys: Vec<String> },
+    /// Removes keys from a shard, which must not be locked.
+    RemoveKeys { shard_id: String, keys: Vec<String> },
+    /// Moves a shard's keys into two new shards.
+    SplitShard {
+        shard_id: String,
+        left_id: String,
+        right_id: String,
+        /// The keys to move to the left shard. If not set, the first half of the keys in
+        /// sorted order are moved there.
+        #[structopt(long = "left-key")]
+        left_keys: Vec<String>,
+    },
+    /// Moves the keys of several shards into one new shard.
+    MergeShards {
+        new_shard_id: String,
+        shard_ids: Vec<String>,
+    },
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let opts = Opts::from_args();
+    let mut client = client::LockServiceClient::connect(opts.endpoint)?;
+
+    match opts.command {
+        Command::Queues { shard_ids } => {
+            let shards = client
+                .queues(Request::new(QueuesRequest { shard_ids }))
+                .await?
+                .into_inner()
+                .shards;
+            // Sort the shards so the output is stable.
+            for (shard_id, queue) in shards.into_iter().collect::<BTreeMap<_, _>>() {
+                println!("{}\t{} waiting", shard_id, queue.waiters.len());
+                for holder in &queue.holders {
+                    println!("\theld by\t{}", describe(holder));
+                }
+                for waiter in &queue.waiters {
+                    println!("\twaiting\t{}", describe(waiter));
+                }
+            }
+        }
+        Command::Cancel {
+            shard_id,
+            client_id,
+        } => {
+            let cancelled = client
+                .cancel(Request::new(CancelRequest {
+                    shard_id,
+                    client_id,
+                }))
+                .await?
+                .into_inner()
+                .cancelled;
+            println!("Cancelled {} requests", cancelled);
+        }
+        Command::Stats => {
+            let stats = client
+                .stats(Request::new(StatsRequest {}))
+                .await?
+                .into_inner();
+            println!("grants:\t{}", stats.grants);
+            println!("releases deferred:\t{}", stats.releases_deferred);
+            println!("handoffs avoided:\t{}", stats.handoffs_avoided);
+            println!("keys expired:\t{}", stats.keys_expired);
+            println!("hot shards split:\t{}", stats.hot_shards_split);
+            println!("deltas sent:\t{}", stats.deltas_sent);
+            println!("full resyncs:\t{}", stats.full_resyncs);
+        }
+        Command::ListShards { keys } => {
+            let shards = client
+                .list_shards(Request::new(ListShardsRequest {}))
+                .await?
+                .into_inner()
+                .shards;
+            for shard in shards {
+                println!("{}\t{} keys", shard.shard_id, shard.keys.len());
+                if keys {
+                    for key in &shard.keys {
+                        println!("\t{}", key);
+                    }
+                }
+            }
+        }
+        Command::CreateShard { shard_id, keys } => {
+            client
+                .create_shard(Request::new(CreateShardRequest {
+                    shard_id: shard_id.clone(),
+                    keys,
+                }))
+                .await?;
+            println!("Created shard {}", shard_id);
+        }
+        Command::DeleteShard { shard_id } => {
+            client
+                .delete_shard(Request::new(DeleteShardRequest {
+                    shard_id: shard_id.clone(),
+                }))
+                .await?;
+            println!("Deleted shard {}", shard_id);
+        }
+        Command::AddKeys { shard_id, keys } => {
+            let changed = client
+                .add_keys(Request::new(KeysRequest { shard_id, keys }))
+                .await?
+                .into_inner()
+                .changed;
+            println!("Added {} keys", changed);
+        }
+        Command::RemoveKeys { shard_id, keys } => {
+            let changed = client
+                .remove_keys(Request::new(KeysRequest { shard_id, keys }))
+                .await?
+                .into_inner()
+                .changed;
+            println!("Removed {} keys", changed);
+        }
+        Command::SplitShard {
+            shard_id,
+            left_id,
+            right_id,
+            left_keys,
+        } => {
+            client
+                .split_shard(Request::new(SplitShardRequest {
+                    shard_id: shard_id.clone(),
+                    left_id: left_id.clone(),
+                    right_id: right_id.clone(),
+                    left_keys,
+                }))
+                .await?;
+            println!("Split shard {} into {} and {}", shard_id, left_id, right_id);
+        }
+        Command::MergeShards {
+            new_shard_id,
+            shard_ids,
+        } => {
+            client
+                .merge_shards(Request::new(MergeShardsRequest {
+                    shard_ids: shard_ids.clone(),
+                    new_shard_id: new_shard_id.clone(),
+                }))
+                .await?;
+            println!(
+                "Merged shards {} into {}",
+                shard_ids.join(", "),
+                new_shard_id
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn describe(participant: &Participant) -> String {
+    format!(
+        "{} ({:?}, priority {}, {} ms)",
+        participant.client_id,
+        participant.mode(),
+        participant.priority,
+        participant.elapsed_millis
+    )
+}
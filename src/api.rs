@@ -1,13 +1,229 @@
+use std::mem::replace;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use tonic::{Code, Status};
 
 tonic::include_proto!("shardik");
 
+/// How long to wait for a shard which can't be granted immediately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wait {
+    Forever,
+    Never,
+    For(Duration),
+}
+
+impl Acquire {
+    pub fn new(shard_id: String, client_id: String, mode: Mode, priority: u32, wait: Wait) -> Self {
+        let (no_wait, timeout_millis) = match wait {
+            Wait::Forever => (false, 0),
+            Wait::Never => (true, 0),
+            Wait::For(timeout) => (false, timeout.as_millis().max(1) as u64),
+        };
+        Acquire {
+            shard_id,
+            mode: mode as i32,
+            client_id,
+            priority,
+            no_wait,
+            timeout_millis,
+            known_version: 0,
+        }
+    }
+
+    pub fn wait(&self) -> Wait {
+        if self.no_wait {
+            Wait::Never
+        } else if self.timeout_millis != 0 {
+            Wait::For(Duration::from_millis(self.timeout_millis))
+        } else {
+            Wait::Forever
+        }
+    }
+}
+
+impl KeyLock {
+    pub fn is_locked(&self) -> bool {
+        self.count > 0
+    }
+
+    /// Whether the key is locked, but its lock has expired. Only the server, which set the
+    /// expiry by its own clock, can tell.
+    pub fn is_expired(&self) -> bool {
+        self.is_locked() && self.expires_at_millis != 0 && self.expires_at_millis <= now_millis()
+    }
+
+    /// Locks the key for `owner`, returning false if it is locked by someone else. Locking a
+    /// key which `owner` already holds just counts it again. If `ttl` is set the lock
+    /// expires after it once the server has the shard back, and locking the key again pushes
+    /// back the expiry.
+    pub fn lock(&mut self, owner: &str, ttl: Option<Duration>) -> bool {
+        if !self.is_locked() {
+            *self = KeyLock {
+                owner: owner.to_owned(),
+                count: 1,
+                acquired_at_millis: now_millis(),
+                ..KeyLock::default()
+            };
+        } else if self.owner == owner {
+            self.count += 1;
+        } else {
+            return false;
+        }
+        self.ttl_millis = ttl.map_or(0, |ttl| ttl.as_millis() as u64);
+        self.expires_at_millis = 0;
+        true
+    }
+
+    /// Whether `owner` may unlock the key. Keys locked before owners were recorded have none,
+    /// and may be unlocked by anyone.
+    pub fn is_owned_by(&self, owner: &str) -> bool {
+        self.owner.is_empty() || self.owner == owner
+    }
+
+    /// Undoes one call to `lock`. The key is unlocked once every call has been undone.
+    pub fn unlock(&mut self) {
+        self.count = self.count.saturating_sub(1);
+        if self.count == 0 {
+            *self = KeyLock::default();
+        }
+    }
+
+    /// Unlocks the key if its lock has expired, returning the expired lock.
+    pub fn expire(&mut self) -> Option<KeyLock> {
+        if self.is_expired() {
+            Some(replace(self, KeyLock::default()))
+        } else {
+            None
+        }
+    }
+}
+
+impl ShardData {
+    /// Moves any locks recorded in `old_locks`, before keys recorded who locked them, into
+    /// `locks`. A key locked back then has no owner, so anyone may unlock it, as they could
+    /// before.
+    pub fn upgrade(&mut self) {
+        for (key, locked) in self.old_locks.drain() {
+            let lock = self.locks.entry(key).or_default();
+            if locked && !lock.is_locked() {
+                lock.count = 1;
+            }
+        }
+    }
+
+    /// Sets when each lock with a TTL expires, if it hasn't been set since the key was last
+    /// locked. The server does this when a shard is released, so that expiries are only
+    /// ever compared with the clock they were set by.
+    pub fn stamp_expiries(&mut self) {
+        let now = now_millis();
+        for lock in self.locks.values_mut() {
+            if lock.is_locked() && lock.ttl_millis != 0 && lock.expires_at_millis == 0 {
+                lock.expires_at_millis = now + lock.ttl_millis;
+            }
+        }
+    }
+
+    /// Unlocks every key whose lock has expired, returning the expired locks.
+    pub fn expire_locks(&mut self) -> Vec<(String, KeyLock)> {
+        self.locks
+            .iter_mut()
+            .filter_map(|(key, lock)| Some((key.clone(), lock.expire()?)))
+            .collect()
+    }
+
+    /// Whether this only has the changes since `base_version`, rather than all of the data.
+    pub fn is_delta(&self) -> bool {
+        self.base_version != 0
+    }
+
+    /// Gets the changes from `base` to this data, to send to someone who already has `base`
+    /// instead of all of it.
+    pub fn delta_from(&self, base: &ShardData) -> ShardData {
+        let locks = self
+            .locks
+            .iter()
+            .filter(|&(key, lock)| base.locks.get(key) != Some(lock))
+            .map(|(key, lock)| (key.clone(), lock.clone()))
+            .collect();
+        let removed_keys = base
+            .locks
+            .keys()
+            .filter(|key| !self.locks.contains_key(*key))
+            .cloned()
+            .collect();
+        ShardData {
+            locks,
+            deleted: self.deleted,
+            fencing_token: self.fencing_token,
+            version: self.version,
+            base_version: base.version,
+            removed_keys,
+            ..ShardData::default()
+        }
+    }
+
+    /// Adds the changes in `next`, a delta from this delta's version, so that this goes
+    /// straight from its base version to the version of `next`.
+    pub fn merge_delta(&mut self, next: ShardData) {
+        for key in next.removed_keys {
+            self.locks.remove(&key);
+            if !self.removed_keys.contains(&key) {
+                self.removed_keys.push(key);
+            }
+        }
+        for (key, lock) in next.locks {
+            self.removed_keys.retain(|removed| *removed != key);
+            self.locks.insert(key, lock);
+        }
+        self.deleted = next.deleted;
+        self.fencing_token = next.fencing_token;
+        self.version = next.version;
+    }
+
+    /// Turns data sent by someone else back into all of the data. If it is a delta it is
+    /// applied to `base`, which must be the version it was made from.
+    pub fn resolve(self, base: Option<&ShardData>) -> Result<ShardData, Status> {
+        if !self.is_delta() {
+            return Ok(self);
+        }
+        let mut data = match base {
+            Some(base) if base.version == self.base_version => base.clone(),
+            _ => {
+                return Err(Status::new(
+                    Code::FailedPrecondition,
+                    format!(
+                        "no data at version {} to apply changes to",
+                        self.base_version
+                    ),
+                ))
+            }
+        };
+        for key in &self.removed_keys {
+            data.locks.remove(key);
+        }
+        data.locks.extend(self.locks);
+        data.deleted = self.deleted;
+        data.fencing_token = self.fencing_token;
+        data.version = self.version;
+        Ok(data)
+    }
+}
+
+/// The current time in milliseconds since the Unix epoch.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
 impl LockRequest {
-    pub fn expect_acquire(self) -> Result<String, Status> {
+    pub fn expect_acquire(self) -> Result<Acquire, Status> {
         match self {
             LockRequest {
-                body: Some(lock_request::Body::Acquire(id)),
-            } => Ok(id),
+                body: Some(lock_request::Body::Acquire(acquire)),
+            } => Ok(acquire),
             _ => Err(Status::new(
                 Code::FailedPrecondition,
                 "expected request to be `acquire`",
@@ -28,8 +244,21 @@ impl LockRequest {
     }
 }
 
+impl RequestError {
+    pub fn from_status(status: &Status) -> Self {
+        RequestError {
+            code: status.code() as i32,
+            message: status.message().to_owned(),
+        }
+    }
+
+    pub fn into_status(self) -> Status {
+        Status::new(Code::from_i32(self.code), self.message)
+    }
+}
+
 impl LockResponse {
-    pub fn expect_acquired(self) -> Result<ShardData, Box<dyn std::error::Error>> {
+    pub fn expect_acquired(self) -> Result<Acquired, Box<dyn std::error::Error>> {
         match self {
             LockResponse {
                 body: Some(lock_response::Body::Acquired(data)),
@@ -47,3 +276,136 @@ impl LockResponse {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn locked_by(owner: &str) -> KeyLock {
+        KeyLock {
+            owner: owner.to_owned(),
+            count: 1,
+            ..KeyLock::default()
+        }
+    }
+
+    fn data(version: u64, locks: &[(&str, KeyLock)]) -> ShardData {
+        ShardData {
+            locks: locks
+                .iter()
+                .map(|(key, lock)| (key.to_string(), lock.clone()))
+                .collect(),
+            version,
+            ..ShardData::default()
+        }
+    }
+
+    #[test]
+    fn delta_has_only_the_changes() {
+        let base = data(
+            1,
+            &[
+                ("a", KeyLock::default()),
+                ("b", KeyLock::default()),
+                ("c", KeyLock::default()),
+            ],
+        );
+        let next = data(2, &[("a", KeyLock::default()), ("b", locked_by("x"))]);
+        let delta = next.delta_from(&base);
+        assert!(delta.is_delta());
+        assert_eq!(delta.base_version, 1);
+        assert_eq!(delta.version, 2);
+        assert_eq!(delta.locks.keys().collect::<Vec<_>>(), vec!["b"]);
+        assert_eq!(delta.removed_keys, vec!["c".to_owned()]);
+    }
+
+    #[test]
+    fn resolving_a_delta_gives_the_data_it_was_made_from() {
+        let base = data(1, &[("a", KeyLock::default()), ("b", KeyLock::default())]);
+        let next = ShardData {
+            fencing_token: 7,
+            ..data(2, &[("a", locked_by("x")), ("c", KeyLock::default())])
+        };
+        let resolved = next.delta_from(&base).resolve(Some(&base)).unwrap();
+        assert_eq!(resolved, next);
+    }
+
+    #[test]
+    fn resolving_full_data_needs_no_base() {
+        let full = data(3, &[("a", locked_by("x"))]);
+        assert_eq!(full.clone().resolve(None).unwrap(), full);
+    }
+
+    #[test]
+    fn resolving_a_delta_fails_without_its_base() {
+        let base = data(1, &[("a", KeyLock::default())]);
+        let next = data(2, &[("a", locked_by("x"))]);
+        let delta = next.delta_from(&base);
+        let other = data(5, &[("a", KeyLock::default())]);
+        let status = delta.clone().resolve(Some(&other)).unwrap_err();
+        assert_eq!(status.code(), Code::FailedPrecondition);
+        assert!(delta.resolve(None).is_err());
+    }
+
+    #[test]
+    fn merged_deltas_go_from_the_first_base_to_the_last_version() {
+        let v1 = data(1, &[("a", KeyLock::default()), ("b", KeyLock::default())]);
+        let v2 = data(2, &[("a", locked_by("x"))]);
+        let v3 = data(3, &[("a", locked_by("x")), ("b", locked_by("y"))]);
+
+        let mut delta = v2.delta_from(&v1);
+        delta.merge_delta(v3.delta_from(&v2));
+        assert_eq!(delta.base_version, 1);
+        assert_eq!(delta.version, 3);
+        // `b` was removed and then added back, so it is only a change.
+        assert!(delta.removed_keys.is_empty());
+        assert_eq!(delta.clone().resolve(Some(&v1)).unwrap(), v3);
+    }
+
+    #[test]
+    fn merging_keeps_keys_removed_later() {
+        let v1 = data(1, &[("a", KeyLock::default()), ("b", KeyLock::default())]);
+        let v2 = data(2, &[("a", locked_by("x")), ("b", KeyLock::default())]);
+        let v3 = data(3, &[("b", KeyLock::default())]);
+
+        let mut delta = v2.delta_from(&v1);
+        delta.merge_delta(v3.delta_from(&v2));
+        assert!(delta.locks.is_empty());
+        assert_eq!(delta.removed_keys, vec!["a".to_owned()]);
+        assert_eq!(delta.resolve(Some(&v1)).unwrap(), v3);
+    }
+
+    #[test]
+    fn upgrading_keeps_old_locks() {
+        let mut data = ShardData::default();
+        data.old_locks.insert("a".to_owned(), true);
+        data.old_locks.insert("b".to_owned(), false);
+        data.upgrade();
+
+        assert!(data.old_locks.is_empty());
+        assert!(data.locks["a"].is_locked());
+        assert!(data.locks["a"].is_owned_by("anyone"));
+        assert!(!data.locks["a"].clone().lock("anyone", None));
+        assert_eq!(data.locks["b"], KeyLock::default());
+        assert!(!locked_by("x").is_owned_by("y"));
+    }
+
+    #[test]
+    fn expiry_is_stamped_once_released() {
+        let mut lock = KeyLock::default();
+        assert!(lock.lock("x", Some(Duration::from_secs(60))));
+        let mut data = data(1, &[("a", lock), ("b", locked_by("y"))]);
+        assert_eq!(data.locks["a"].expires_at_millis, 0);
+
+        data.stamp_expiries();
+        let expires_at = data.locks["a"].expires_at_millis;
+        assert!(expires_at >= now_millis() + 59_000);
+        assert!(!data.locks["a"].is_expired());
+        assert_eq!(data.locks["b"].expires_at_millis, 0);
+
+        // Locking the key again pushes back the expiry, once it is stamped again.
+        let lock = data.locks.get_mut("a").unwrap();
+        assert!(lock.lock("x", Some(Duration::from_secs(60))));
+        assert_eq!(lock.expires_at_millis, 0);
+    }
+}
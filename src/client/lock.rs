@@ -1,137 +1,929 @@
 use std::collections::hash_map::{self, HashMap};
+use std::collections::BTreeSet;
 use std::mem::replace;
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::time::{Duration, Instant};
+use std::{cmp, error, fmt};
 
 use futures::channel::mpsc;
 use futures::future::join_all;
-use futures::{SinkExt, Stream, StreamExt};
-use tonic::transport::Channel;
-use tonic::{Request, Status};
+use tokio::timer;
+use tonic::{Code, Request, Status};
 
+use crate::router::Router;
+use crate::session::{CacheEntry, Session};
 use shardik::api::*;
 use shardik::metrics::Metrics;
-use shardik::resource::Resource;
+use shardik::resource::Fence;
 
-pub struct Lock<R> {
-    client: client::LockServiceClient<Channel>,
+/// The number of times to retry acquiring a shard when redirected or the server is
+/// unavailable.
+const MAX_ATTEMPTS: u32 = 8;
+const RETRY_DELAY: Duration = Duration::from_millis(250);
+
+pub struct Lock {
+    router: Router,
+    /// The open session with each server, by endpoint.
+    sessions: HashMap<String, Session>,
     cache: HashMap<String, CacheEntry>,
-    resource: Arc<R>,
+    /// The data each shard was last granted with, including shards no longer cached. Asking
+    /// for a shard again with its version lets the server send only what has changed.
+    known: HashMap<String, Arc<ShardData>>,
+    /// Shards given up on the way to acquiring others, which are released along with the
+    /// next acquire request on the same session.
+    releasing: Vec<CacheEntry>,
+    /// Whether to send releases along with the next acquire request, rather than in messages
+    /// of their own.
+    piggyback_releases: bool,
+    /// The shard each key belongs to, as last fetched from the servers or pushed by them.
+    routes: HashMap<String, String>,
+    /// The version of the routes we have from each server, by endpoint. If a server grants
+    /// a shard with a different version, our routes are out of date.
+    route_versions: HashMap<String, u64>,
+    /// The number of times the routes have been updated.
+    route_updates: u64,
     client_name: Option<String>,
+    /// The name recorded as the owner of keys we lock. This is the client name if there is
+    /// one, otherwise it is made up.
+    owner: String,
+    /// The priority to request shards with.
+    priority: u32,
+    /// How long key locks last once the server has their shard back, before it clears
+    /// them, if they expire at all.
+    key_ttl: Option<Duration>,
     metrics: Metrics,
+    /// The number of sessions which ended while we were using them, so the server
+    /// reclaimed any shards held on them.
+    sessions_lost: u64,
+    /// Keys whose guards were dropped while their shard wasn't cached, which still need to
+    /// be unlocked.
+    pending_unlocks: Arc<Mutex<Vec<String>>>,
+}
+
+#[derive(Debug)]
+pub enum LockError {
+    /// The key is already locked, either by another client or by an earlier call.
+    AlreadyLocked(String),
+    /// The key was still locked by someone else when we gave up waiting for it.
+    StillLocked(String),
+    /// The key's shard doesn't have it.
+    KeyNotFound(String),
+    /// The server doesn't have the shards.
+    ShardNotFound {
+        shard_ids: Vec<String>,
+    },
+    /// Tried to unlock a key which isn't locked.
+    NotLocked(String),
+    /// Tried to unlock a key which was locked by someone else.
+    NotOwner {
+        key: String,
+        owner: String,
+    },
+    /// The shards are held by another client, and we weren't willing to wait for them.
+    WouldBlock {
+        shard_ids: Vec<String>,
+    },
+    /// The shards weren't granted within the time we were willing to wait for them.
+    TimedOut {
+        shard_ids: Vec<String>,
+    },
+    /// The server aborted the request for the shards to break a deadlock with other clients.
+    /// Releasing any shards held before trying again lets the other clients make progress.
+    Deadlock {
+        shard_ids: Vec<String>,
+    },
+    /// The shards were redirected or stolen too many times in a row.
+    TooManyAttempts,
+    /// The stream to the server holding a shard was closed.
+    Disconnected,
+    /// Any other error from the server.
+    Status(Status),
+    Metrics(csv::Error),
 }
 
-/// Represents cached shard data. If `data` is Some then it is cached. If it is none then
-/// it has been recently stolen by another client.
-#[derive(Debug, Clone)]
-struct CacheEntry {
+/// A locked key, which is unlocked when the guard is dropped.
+///
+/// Unlocking needs the key's shard. If it is still cached the key is unlocked straight away,
+/// otherwise it is left for the next call on the `Lock` the guard came from.
+#[must_use = "the key is unlocked as soon as the guard is dropped"]
+pub struct LockGuard {
+    key: String,
+    owner: String,
     data: Arc<Mutex<Option<ShardData>>>,
-    request_tx: mpsc::Sender<Result<LockRequest, Status>>,
+    pending_unlocks: Arc<Mutex<Vec<String>>>,
+}
+
+/// The server's answer to a request for some shards, and the endpoint of the server.
+enum Grant {
+    /// The shards were granted to the request with the given id in the session.
+    Acquired(String, HashMap<String, Acquired>, Session, u64),
+    Redirect(Redirect),
+    /// The server doesn't have the shards, and sent the shard each of its keys belongs to.
+    Rerouted(String, RoutesResponse),
+}
+
+/// Shards granted together by the server, which haven't been cached yet.
+struct Granted {
+    data: HashMap<String, ShardData>,
+    /// The data as it was granted, before any changes.
+    bases: HashMap<String, Arc<ShardData>>,
+    mode: Mode,
+    session: Session,
+    request_id: u64,
 }
 
-impl<R: Resource> Lock<R> {
+impl Lock {
     pub fn new(
         client_name: Option<String>,
-        client: client::LockServiceClient<Channel>,
-        resource: Arc<R>,
+        router: Router,
+        priority: u32,
+        key_ttl: Option<Duration>,
+        piggyback_releases: bool,
         metrics: Metrics,
     ) -> Self {
+        let owner = client_name
+            .clone()
+            .unwrap_or_else(|| format!("anonymous-{:016x}", rand::random::<u64>()));
         Lock {
-            client,
+            router,
+            sessions: HashMap::new(),
             cache: HashMap::new(),
-            resource,
+            known: HashMap::new(),
+            releasing: Vec::new(),
+            piggyback_releases,
+            routes: HashMap::new(),
+            route_versions: HashMap::new(),
+            route_updates: 0,
             client_name,
+            owner,
+            priority,
+            key_ttl,
             metrics,
+            sessions_lost: 0,
+            pending_unlocks: Arc::default(),
+        }
+    }
+
+    /// Asks the server for the leader of its cluster, and uses it by default.
+    pub async fn discover_leader(&mut self) -> Result<(), LockError> {
+        let endpoint = self.router.default_endpoint();
+        let leader = self
+            .router
+            .client(&endpoint)
+            .map_err(|err| Status::new(Code::Unavailable, err.to_string()))?
+            .leader(Request::new(LeaderRequest {}))
+            .await?
+            .into_inner()
+            .endpoint;
+        if !leader.is_empty() {
+            self.router.set_leader(leader);
+        }
+        Ok(())
+    }
+
+    /// Fetches which shard each key belongs to from the servers. This needs doing before
+    /// locking any keys, and again whenever shards are split or merged, which is noticed
+    /// when acquiring shards.
+    pub async fn refresh_routes(&mut self) -> Result<(), LockError> {
+        let mut routes = HashMap::new();
+        let mut versions = HashMap::new();
+        for endpoint in self.router.servers() {
+            let response = self
+                .router
+                .client(&endpoint)
+                .map_err(|err| Status::new(Code::Unavailable, err.to_string()))?
+                .routes(Request::new(RoutesRequest {}))
+                .await?
+                .into_inner();
+            routes.extend(response.shards);
+            versions.insert(endpoint, response.version);
+        }
+        log::info!("Fetched routes for {} keys", routes.len());
+        self.routes = routes;
+        self.route_versions = versions;
+        self.route_updates += 1;
+        Ok(())
+    }
+
+    /// Locks a key, returning false if it is locked by someone else. Locking a key we have
+    /// already locked succeeds, and it then needs unlocking as many times as it was locked.
+    pub async fn lock(&mut self, key: &str) -> Result<bool, LockError> {
+        self.lock_wait(key, Wait::Forever).await
+    }
+
+    /// Like `lock`, but gives up if the shard for the key can't be acquired within the time
+    /// allowed by `wait`. Giving up is reported as `WouldBlock` or `TimedOut`.
+    pub async fn lock_wait(&mut self, key: &str, wait: Wait) -> Result<bool, LockError> {
+        self.unlock_pending().await?;
+        let start = Instant::now();
+        let result = self.set_locked(key, wait).await;
+        self.log_metrics(key, start.elapsed(), Duration::from_secs(0))?;
+        result
+    }
+
+    /// Locks several keys at once, returning whether each one was locked. Any shards
+    /// for the keys which aren't already cached are requested together, so that they are
+    /// granted all together or not at all.
+    pub async fn lock_many(&mut self, keys: &[&str]) -> Result<Vec<bool>, LockError> {
+        self.unlock_pending().await?;
+        let start = Instant::now();
+        let updates = self.route_updates;
+        let result = match self.set_many_locked(keys).await {
+            Err(ref err) if is_misrouted(err) => {
+                self.reroute(updates).await?;
+                self.set_many_locked(keys).await
+            }
+            result => result,
+        };
+        for key in keys {
+            self.log_metrics(key, start.elapsed(), Duration::from_secs(0))?;
+        }
+        result
+    }
+
+    /// Locks a key, returning a guard which unlocks it when dropped. Waits for the shard
+    /// for the key like `lock_wait`, but fails with `AlreadyLocked` if the key is already
+    /// locked.
+    pub async fn lock_guard(&mut self, key: &str, wait: Wait) -> Result<LockGuard, LockError> {
+        if !self.lock_wait(key, wait).await? {
+            return Err(LockError::AlreadyLocked(key.to_owned()));
         }
+        self.guard(key)
     }
 
-    pub async fn lock(&mut self, key: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    /// Like `lock_guard`, but fails with `WouldBlock` rather than waiting if the shard for
+    /// the key is held by another client.
+    pub async fn try_lock(&mut self, key: &str) -> Result<LockGuard, LockError> {
+        self.lock_guard(key, Wait::Never).await
+    }
+
+    /// Like `lock_guard`, but if the key is already locked waits until it is unlocked. Fails
+    /// with `StillLocked` if the key isn't unlocked within `timeout`, which also limits how
+    /// long to wait for the shard.
+    ///
+    /// While waiting, the shard is handed back so that whoever has the key locked can
+    /// unlock it, and requested again after a short delay.
+    pub async fn wait_lock(
+        &mut self,
+        key: &str,
+        timeout: Option<Duration>,
+    ) -> Result<LockGuard, LockError> {
+        self.unlock_pending().await?;
         let start = Instant::now();
-        let result = self.set_locked(key, true).await;
-        self.metrics.log(&self.client_name, key, start.elapsed())?;
+        let deadline = timeout.map(|timeout| start + timeout);
+        let mut locked_since = None;
+        let result = loop {
+            let now = Instant::now();
+            let wait = match deadline {
+                None => Wait::Forever,
+                Some(deadline) if now < deadline => Wait::For(deadline - now),
+                Some(_) => Wait::Never,
+            };
+            match self.set_locked(key, wait).await {
+                Ok(true) => break self.guard(key),
+                Ok(false) => {}
+                Err(err) => break Err(err),
+            }
+
+            let since = *locked_since.get_or_insert(now);
+            log::debug!("Key {} is locked, waiting for it to be unlocked", key);
+            let shard_id = match self.shard_id(key) {
+                Ok(shard_id) => shard_id,
+                Err(err) => break Err(err),
+            };
+            if let Some(mut entry) = self.cache.remove(&shard_id) {
+                if let Err(err) = entry.release().await {
+                    break Err(err.into());
+                }
+            }
+
+            let delay = match deadline {
+                None => RETRY_DELAY,
+                Some(deadline) if deadline > Instant::now() => {
+                    cmp::min(RETRY_DELAY, deadline - Instant::now())
+                }
+                Some(_) => {
+                    log::info!(
+                        "Key {} still locked after waiting {:?}",
+                        key,
+                        since.elapsed()
+                    );
+                    break Err(LockError::StillLocked(key.to_owned()));
+                }
+            };
+            timer::delay_for(delay).await;
+        };
+
+        let key_wait = locked_since.map_or(Duration::from_secs(0), |since| since.elapsed());
+        self.log_metrics(key, start.elapsed(), key_wait)?;
         result
     }
 
-    pub async fn unlock(&mut self, key: &str) -> Result<(), Box<dyn std::error::Error>> {
-        assert!(self.set_locked(key, false).await?);
+    /// Unlocks a key. Fails with `NotOwner` if it was locked by someone else.
+    pub async fn unlock(&mut self, key: &str) -> Result<(), LockError> {
+        self.unlock_pending().await?;
+        self.set_unlocked(key).await
+    }
+
+    /// Checks whether a key is locked, without locking it. This only needs the shard in
+    /// shared mode, so it can run alongside other readers.
+    pub async fn is_locked(&mut self, key: &str) -> Result<bool, LockError> {
+        self.unlock_pending().await?;
+        let updates = self.route_updates;
+        match self.get_locked(key).await {
+            Err(ref err) if is_misrouted(err) => {
+                self.reroute(updates).await?;
+                self.get_locked(key).await
+            }
+            result => result,
+        }
+    }
+
+    /// Gets the fencing token to access a key with, or `None` if its shard is no longer
+    /// cached, in which case someone else may have it.
+    pub fn fence(&self, key: &str) -> Option<Fence> {
+        let shard_id = self.routes.get(key)?;
+        let data = self.cache.get(shard_id)?.data.lock().unwrap();
+        Some(Fence {
+            shard_id: shard_id.clone(),
+            token: data.as_ref()?.fencing_token,
+        })
+    }
+
+    /// Gets the id of the shard a key belongs to, or `KeyNotFound` if our routes don't
+    /// have it.
+    fn shard_id(&self, key: &str) -> Result<String, LockError> {
+        self.routes
+            .get(key)
+            .cloned()
+            .ok_or_else(|| LockError::KeyNotFound(key.to_owned()))
+    }
+
+    /// Brings the routes up to date after a request failed because they were out of date,
+    /// unless they have already been updated since there had been `updates` updates.
+    async fn reroute(&mut self, updates: u64) -> Result<(), LockError> {
+        if self.route_updates == updates {
+            self.refresh_routes().await?;
+        }
         Ok(())
     }
 
-    async fn set_locked(
+    /// Adds routes pushed by the server at `endpoint` to the ones we know. Each server only
+    /// sends routes for its own keys, so these don't replace the routes from other servers.
+    fn merge_routes(&mut self, endpoint: String, routes: RoutesResponse) {
+        log::info!(
+            "Received routes for {} keys from {} (version {})",
+            routes.shards.len(),
+            endpoint,
+            routes.version
+        );
+        self.routes.extend(routes.shards);
+        self.route_versions.insert(endpoint, routes.version);
+        self.route_updates += 1;
+    }
+
+    async fn get_locked(&mut self, key: &str) -> Result<bool, LockError> {
+        let get = |data: &mut ShardData| data.locks.get(key).map(KeyLock::is_locked);
+
+        let shard_id = self.shard_id(key)?;
+        let locked = match self.with_cached(&shard_id, Mode::Shared, get) {
+            Some(locked) => locked,
+            None => {
+                self.acquire(shard_id, Mode::Shared, Wait::Forever, get)
+                    .await?
+            }
+        };
+        locked.ok_or_else(|| LockError::KeyNotFound(key.to_owned()))
+    }
+
+    /// Makes a guard for a key which has just been locked, so its shard is cached. Fails
+    /// with `KeyNotFound` if the key was moved to another shard while it was being locked, in
+    /// which case it is left to be unlocked by the next call.
+    fn guard(&self, key: &str) -> Result<LockGuard, LockError> {
+        let entry = match self
+            .routes
+            .get(key)
+            .and_then(|shard_id| self.cache.get(shard_id))
+        {
+            Some(entry) => entry,
+            None => {
+                self.pending_unlocks.lock().unwrap().push(key.to_owned());
+                return Err(LockError::KeyNotFound(key.to_owned()));
+            }
+        };
+        Ok(LockGuard {
+            key: key.to_owned(),
+            owner: self.owner.clone(),
+            data: entry.data.clone(),
+            pending_unlocks: self.pending_unlocks.clone(),
+        })
+    }
+
+    fn log_metrics(&mut self, key: &str, dur: Duration, key_wait: Duration) -> csv::Result<()> {
+        self.metrics
+            .log(&self.client_name, key, dur, key_wait, self.priority)
+    }
+
+    /// Unlocks the keys of any guards dropped while their shard wasn't cached.
+    async fn unlock_pending(&mut self) -> Result<(), LockError> {
+        loop {
+            let key = match self.pending_unlocks.lock().unwrap().pop() {
+                Some(key) => key,
+                None => return Ok(()),
+            };
+            match self.set_unlocked(&key).await {
+                Ok(()) => {}
+                // Someone else may have legitimately locked the key since.
+                Err(err @ LockError::NotLocked(_)) | Err(err @ LockError::NotOwner { .. }) => {
+                    log::warn!("Failed to unlock key: {}", err);
+                }
+                Err(err) => {
+                    self.pending_unlocks.lock().unwrap().push(key);
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    async fn set_locked(&mut self, key: &str, wait: Wait) -> Result<bool, LockError> {
+        let owner = self.owner.clone();
+        let ttl = self.key_ttl;
+        self.update_key(key, wait, |lock| lock.lock(&owner, ttl))
+            .await
+    }
+
+    async fn set_unlocked(&mut self, key: &str) -> Result<(), LockError> {
+        let owner = self.owner.clone();
+        self.update_key(key, Wait::Forever, |lock| {
+            if !lock.is_locked() {
+                Err(LockError::NotLocked(key.to_owned()))
+            } else if !lock.is_owned_by(&owner) {
+                Err(LockError::NotOwner {
+                    key: key.to_owned(),
+                    owner: lock.owner.clone(),
+                })
+            } else {
+                lock.unlock();
+                Ok(())
+            }
+        })
+        .await?
+    }
+
+    /// Runs `f` on the lock for a key, holding its shard exclusively.
+    async fn update_key<T>(
         &mut self,
         key: &str,
-        value: bool,
-    ) -> Result<bool, Box<dyn std::error::Error>> {
-        let set = |data: &mut ShardData| replace(data.locks.get_mut(key).unwrap(), value) != value;
+        wait: Wait,
+        mut f: impl FnMut(&mut KeyLock) -> T,
+    ) -> Result<T, LockError> {
+        let updates = self.route_updates;
+        match self.update_key_once(key, wait, &mut f).await {
+            Err(ref err) if is_misrouted(err) => {
+                self.reroute(updates).await?;
+                self.update_key_once(key, wait, f).await
+            }
+            result => result,
+        }
+    }
+
+    async fn update_key_once<T>(
+        &mut self,
+        key: &str,
+        wait: Wait,
+        mut f: impl FnMut(&mut KeyLock) -> T,
+    ) -> Result<T, LockError> {
+        let mut set = |data: &mut ShardData| {
+            let lock = data
+                .locks
+                .get_mut(key)
+                .ok_or_else(|| LockError::KeyNotFound(key.to_owned()))?;
+            Ok(f(lock))
+        };
+
+        let shard_id = self.shard_id(key)?;
+        if let Some(result) = self.with_cached(&shard_id, Mode::Exclusive, &mut set) {
+            return result;
+        }
+
+        // Need to acquire the shard from the server. If we only hold it in shared mode, give
+        // that up first so the server can grant it to us exclusively.
+        if let Some(entry) = self.cache.remove(&shard_id) {
+            log::info!("Upgrading shard {} to exclusive mode", shard_id);
+            self.give_up(entry).await?;
+        }
+        // Any expired locks were already cleared by the server when it granted the shard.
+        self.acquire(shard_id, Mode::Exclusive, wait, set).await?
+    }
+
+    async fn set_many_locked(&mut self, keys: &[&str]) -> Result<Vec<bool>, LockError> {
+        // The routes may change while the shards are being acquired, so look the keys up
+        // once. If a key moves in the meantime, it won't be in the shard it was looked up in.
+        let key_shard_ids = keys
+            .iter()
+            .map(|key| self.shard_id(key))
+            .collect::<Result<Vec<_>, _>>()?;
+        let shard_ids: Vec<String> = key_shard_ids
+            .iter()
+            .cloned()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+
+        for _ in 0..MAX_ATTEMPTS {
+            let missing: Vec<String> = shard_ids
+                .iter()
+                .filter(|shard_id| !self.is_cached(shard_id, Mode::Exclusive))
+                .cloned()
+                .collect();
+            if !missing.is_empty() {
+                for shard_id in &missing {
+                    if let Some(entry) = self.cache.remove(shard_id) {
+                        self.give_up(entry).await?;
+                    }
+                }
+                let granted = self
+                    .acquire_shards(&missing, Mode::Exclusive, Wait::Forever)
+                    .await?;
+                self.cache_granted(granted).await?;
+            }
 
-        let shard_id = self.resource.get_shard_id(key);
-        if let hash_map::Entry::Occupied(entry) = self.cache.entry(shard_id.clone()) {
+            // Hold the data for every shard at once, so that none of them can be released
+            // part way through.
+            let entries: Vec<CacheEntry> = shard_ids
+                .iter()
+                .filter_map(|shard_id| self.cache.get(shard_id).cloned())
+                .collect();
+            let mut locks: Vec<_> = entries
+                .iter()
+                .map(|entry| entry.data.lock().unwrap())
+                .collect();
+            if entries.len() < shard_ids.len() || locks.iter().any(|data| data.is_none()) {
+                log::info!("A shard was stolen before it could be used, retrying");
+                continue;
+            }
+
+            // Every key's shard is in `shard_ids`, since that was made from them.
+            let index = |i: usize| shard_ids.binary_search(&key_shard_ids[i]).unwrap();
+            let unknown = keys.iter().enumerate().find(|&(i, key)| {
+                let data = locks[index(i)].as_ref().unwrap();
+                !data.locks.contains_key(*key)
+            });
+            if let Some((_, key)) = unknown {
+                return Err(LockError::KeyNotFound(key.to_string()));
+            }
+
+            let (owner, ttl) = (&self.owner, self.key_ttl);
+            let locked = keys
+                .iter()
+                .enumerate()
+                .map(|(i, key)| {
+                    let data = locks[index(i)].as_mut().unwrap();
+                    data.locks.get_mut(*key).unwrap().lock(owner, ttl)
+                })
+                .collect();
+            return Ok(locked);
+        }
+        Err(LockError::TooManyAttempts)
+    }
+
+    /// Whether a shard is cached in a mode which allows `mode` access.
+    fn is_cached(&self, shard_id: &str, mode: Mode) -> bool {
+        match self.cache.get(shard_id) {
+            Some(entry) => {
+                (mode == Mode::Shared || entry.mode == Mode::Exclusive)
+                    && entry.data.lock().unwrap().is_some()
+            }
+            None => false,
+        }
+    }
+
+    /// Runs `f` on the cached data for a shard, if it is cached in a mode which allows it.
+    fn with_cached<T>(
+        &mut self,
+        shard_id: &str,
+        mode: Mode,
+        f: impl FnOnce(&mut ShardData) -> T,
+    ) -> Option<T> {
+        if let hash_map::Entry::Occupied(entry) = self.cache.entry(shard_id.to_owned()) {
             {
                 let mut lock = entry.get().data.lock().unwrap();
                 if let Some(data) = lock.as_mut() {
                     // The shard is cached.
-                    return Ok(set(data));
+                    if mode == Mode::Shared || entry.get().mode == Mode::Exclusive {
+                        return Some(f(data));
+                    }
+                    return None;
                 }
             }
             // The cached shard was stolen by another thread, remove the entry.
             entry.remove_entry();
         }
-
-        // Need to acquire the shard from the server.
-        self.acquire(shard_id, set).await
+        None
     }
 
-    async fn acquire(
+    async fn acquire<T>(
         &mut self,
         shard_id: String,
-        set: impl FnOnce(&mut ShardData) -> bool,
-    ) -> Result<bool, Box<dyn std::error::Error>> {
-        log::warn!("Acquiring new shard {}", shard_id);
-        let (mut request_tx, request_rx) = mpsc::channel(0);
-        let mut response_rx = self
-            .client
-            .lock(Request::new(request_rx))
-            .await?
-            .into_inner();
-
-        request_tx
-            .send(Ok(LockRequest {
-                body: Some(lock_request::Body::Acquire(shard_id.clone())),
-            }))
-            .await?;
-        let mut data = response_rx.next().await.unwrap()?.expect_acquired()?;
-
-        let result = set(&mut data);
-
-        // Launch a background task to handle releasing the shard lock when requested by
-        // the server.
-        let cache_entry = CacheEntry {
-            data: Arc::new(Mutex::new(Some(data))),
-            request_tx,
+        mode: Mode,
+        wait: Wait,
+        f: impl FnOnce(&mut ShardData) -> T,
+    ) -> Result<T, LockError> {
+        let mut granted = self.acquire_shards(&[shard_id.clone()], mode, wait).await?;
+        let result = f(granted.data.get_mut(&shard_id).unwrap());
+        self.cache_granted(granted).await?;
+        Ok(result)
+    }
+
+    async fn acquire_shards(
+        &mut self,
+        shard_ids: &[String],
+        mode: Mode,
+        wait: Wait,
+    ) -> Result<Granted, LockError> {
+        log::warn!("Acquiring new shards {:?} in {:?} mode", shard_ids, mode);
+        let mut attempts = 0;
+        let (endpoint, acquired, session, request_id) = loop {
+            attempts += 1;
+            match self.request_shards(shard_ids, mode, wait).await {
+                Ok(Grant::Acquired(endpoint, acquired, mut session, request_id)) => {
+                    let unresolved = match self.resolve(acquired) {
+                        Ok(acquired) => break (endpoint, acquired, session, request_id),
+                        Err(unresolved) => unresolved,
+                    };
+                    // We only ever ask for changes since a version we have, so the server is
+                    // confused about it. Give the shards back and ask for all of their data.
+                    for shard_id in &unresolved {
+                        self.known.remove(shard_id);
+                    }
+                    session.give_back(request_id).await?;
+                    if attempts >= MAX_ATTEMPTS {
+                        return Err(LockError::TooManyAttempts);
+                    }
+                }
+                Ok(Grant::Redirect(redirect)) if attempts < MAX_ATTEMPTS => {
+                    log::warn!(
+                        "Redirected to {} for shards {:?}",
+                        redirect.endpoint,
+                        shard_ids
+                    );
+                    self.router.redirect(&shard_ids[0], redirect);
+                }
+                Err(ref status)
+                    if status.code() == Code::Unavailable && attempts < MAX_ATTEMPTS =>
+                {
+                    // The server may be down or in the middle of an election, so try the
+                    // next one we know of.
+                    log::warn!("Server unavailable: {}", status.message());
+                    self.router.next_endpoint();
+                    timer::delay_for(RETRY_DELAY).await;
+                }
+                Ok(Grant::Rerouted(endpoint, routes)) => {
+                    self.merge_routes(endpoint, routes);
+                    return Err(LockError::ShardNotFound {
+                        shard_ids: shard_ids.to_vec(),
+                    });
+                }
+                Ok(Grant::Redirect(_)) => return Err(LockError::TooManyAttempts),
+                Err(status) => return Err(LockError::from_status(status, shard_ids)),
+            }
         };
-        tokio::spawn(handle_release(cache_entry.clone(), response_rx));
-        self.cache.insert(shard_id, cache_entry);
 
-        Ok(result)
+        // Every shard comes from the same server, so has the same routes version.
+        let routes_version = acquired
+            .values()
+            .map(|acquired| acquired.routes_version)
+            .next();
+        let stale = routes_version.map_or(false, |version| {
+            self.route_versions.get(&endpoint) != Some(&version)
+        });
+
+        let mut data = HashMap::new();
+        let mut bases = HashMap::new();
+        for (shard_id, acquired) in acquired {
+            if acquired.unclean {
+                log::warn!(
+                    "Shard {} was not released by its previous holder, some changes may be lost",
+                    shard_id
+                );
+            }
+            let shard_data = acquired.data.unwrap_or_default();
+            bases.insert(shard_id.clone(), Arc::new(shard_data.clone()));
+            data.insert(shard_id, shard_data);
+        }
+        if stale {
+            // The shards we were granted still exist, but some keys may have moved. Failing
+            // to refresh isn't fatal, since a moved key is noticed when it's used.
+            log::info!("Routes from {} are out of date, refreshing", endpoint);
+            if let Err(err) = self.refresh_routes().await {
+                log::warn!("Failed to refresh routes: {}", err);
+            }
+        }
+        Ok(Granted {
+            data,
+            bases,
+            mode,
+            session,
+            request_id,
+        })
     }
 
-    pub async fn release_all(&mut self) {
-        join_all(self.cache.drain().map(|(shard_id, cache_entry)| {
-            async {
-                futures::pin_mut!(shard_id);
-                futures::pin_mut!(cache_entry);
-
-                log::info!("sending released request for shard {}", shard_id);
-                if let Err(err) = cache_entry.release().await {
-                    log::error!("failed to release shard {}: {}", shard_id, err);
+    /// Turns the data for granted shards sent as changes since a version we know back into
+    /// all of the data. Fails with the ids of any shards whose changes are since a version we
+    /// don't know.
+    fn resolve(
+        &self,
+        acquired: HashMap<String, Acquired>,
+    ) -> Result<HashMap<String, Acquired>, Vec<String>> {
+        let mut unresolved = Vec::new();
+        let resolved: HashMap<_, _> = acquired
+            .into_iter()
+            .map(|(shard_id, mut acquired)| {
+                let known = self.known.get(&shard_id).map(|known| &**known);
+                match acquired.data.take().unwrap_or_default().resolve(known) {
+                    Ok(data) => acquired.data = Some(data),
+                    Err(status) => {
+                        log::warn!("Can't use shard {}: {}", shard_id, status.message());
+                        unresolved.push(shard_id.clone());
+                    }
                 }
+                (shard_id, acquired)
+            })
+            .collect();
+        if unresolved.is_empty() {
+            Ok(resolved)
+        } else {
+            Err(unresolved)
+        }
+    }
+
+    /// Adds granted shards to the cache, and has their session release them when the server
+    /// asks.
+    async fn cache_granted(&mut self, mut granted: Granted) -> Result<(), LockError> {
+        let tagged = granted.data.len() > 1;
+        let mut entries = HashMap::new();
+        for (shard_id, data) in granted.data {
+            let base = granted.bases[&shard_id].clone();
+            self.known.insert(shard_id.clone(), base.clone());
+            let cache_entry = granted.session.cache_entry(
+                granted.request_id,
+                shard_id.clone(),
+                data,
+                base,
+                granted.mode,
+                tagged,
+            );
+            self.cache.insert(shard_id.clone(), cache_entry.clone());
+            entries.insert(shard_id, cache_entry);
+        }
+        granted.session.hold(granted.request_id, entries).await?;
+        Ok(())
+    }
+
+    /// Gets the session with the server at `endpoint`, opening one if there isn't one open
+    /// already.
+    async fn session(&mut self, endpoint: &str) -> Result<Session, Status> {
+        if let Some(session) = self.sessions.get(endpoint) {
+            if !session.is_closed() {
+                return Ok(session.clone());
+            }
+            self.sessions.remove(endpoint);
+            self.sessions_lost += 1;
+        }
+        let client_id = self.client_name.clone().unwrap_or_default();
+        let client = self
+            .router
+            .client(endpoint)
+            .map_err(|err| Status::new(Code::Unavailable, err.to_string()))?;
+        let session = Session::open(client, client_id).await?;
+        self.sessions.insert(endpoint.to_owned(), session.clone());
+        Ok(session)
+    }
+
+    /// Requests some shards on the session with the server responsible for them.
+    async fn request_shards(
+        &mut self,
+        shard_ids: &[String],
+        mode: Mode,
+        wait: Wait,
+    ) -> Result<Grant, Status> {
+        let client_id = self.client_name.clone().unwrap_or_default();
+        let mut known_versions: HashMap<String, u64> = shard_ids
+            .iter()
+            .filter_map(|shard_id| Some((shard_id.clone(), self.known.get(shard_id)?.version)))
+            .collect();
+        let body = if shard_ids.len() == 1 {
+            let acquire = Acquire::new(shard_ids[0].clone(), client_id, mode, self.priority, wait);
+            lock_request::Body::Acquire(Acquire {
+                known_version: known_versions.remove(&shard_ids[0]).unwrap_or(0),
+                ..acquire
+            })
+        } else {
+            lock_request::Body::AcquireMany(AcquireMany {
+                shard_ids: shard_ids.to_vec(),
+                options: Some(Acquire::new(
+                    String::new(),
+                    client_id,
+                    mode,
+                    self.priority,
+                    wait,
+                )),
+                known_versions,
+            })
+        };
+
+        let endpoint = self.router.endpoint(&shard_ids[0]);
+        let mut session = self.session(&endpoint).await?;
+        let releases = self.take_releases(&session).await;
+        let request = LockRequest { body: Some(body) };
+        let (request_id, response) = match session.request(request, releases).await {
+            Ok(response) => response,
+            Err(status) => {
+                if status.code() == Code::Unavailable {
+                    // The session has ended, so open a new one next time.
+                    if self.sessions.remove(&endpoint).is_some() {
+                        self.sessions_lost += 1;
+                    }
+                }
+                return Err(status);
+            }
+        };
+        let acquired = match response.body {
+            Some(lock_response::Body::Acquired(acquired)) => {
+                vec![(shard_ids[0].clone(), acquired)].into_iter().collect()
+            }
+            Some(lock_response::Body::AcquiredMany(acquired)) => acquired.shards,
+            Some(lock_response::Body::Redirect(redirect)) => return Ok(Grant::Redirect(redirect)),
+            Some(lock_response::Body::Routes(routes)) => {
+                return Ok(Grant::Rerouted(endpoint, routes))
+            }
+            _ => {
+                return Err(Status::new(
+                    Code::Internal,
+                    "expected response to be `acquired`",
+                ))
+            }
+        };
+        Ok(Grant::Acquired(endpoint, acquired, session, request_id))
+    }
+
+    /// Releases a shard given up on the way to acquiring others, or leaves it to be released
+    /// along with the next acquire request.
+    async fn give_up(&mut self, mut entry: CacheEntry) -> Result<(), LockError> {
+        if self.piggyback_releases {
+            self.releasing.push(entry);
+        } else {
+            entry.release().await?;
+        }
+        Ok(())
+    }
+
+    /// Takes the releases to send along with a request on `session`. Shards granted on other
+    /// sessions are released straight away instead.
+    async fn take_releases(&mut self, session: &Session) -> Vec<ReleasedRequest> {
+        let mut releases = Vec::new();
+        for mut entry in replace(&mut self.releasing, Vec::new()) {
+            if entry.session_id() == session.id() {
+                releases.extend(entry.take_release());
+            } else if let Err(err) = entry.release().await {
+                log::error!("failed to release shard {}: {}", entry.shard_id, err);
+            }
+        }
+        releases
+    }
+
+    pub async fn release_all(&mut self) {
+        if let Err(err) = self.unlock_pending().await {
+            log::error!("failed to unlock keys: {}", err);
+        }
+        let releasing = replace(&mut self.releasing, Vec::new());
+        let entries = releasing
+            .into_iter()
+            .map(|entry| (entry.shard_id.clone(), entry))
+            .chain(self.cache.drain());
+        join_all(entries.map(|(shard_id, cache_entry)| async {
+            futures::pin_mut!(shard_id);
+            futures::pin_mut!(cache_entry);
+
+            log::info!("sending released request for shard {}", shard_id);
+            if let Err(err) = cache_entry.release().await {
+                log::error!("failed to release shard {}: {}", shard_id, err);
             }
         }))
         .await;
     }
-}
 
-impl<R> Lock<R> {
+    /// The number of sessions which ended while we were using them, including any which
+    /// have ended since we last used them.
+    pub fn sessions_lost(&self) -> u64 {
+        let closed = self
+            .sessions
+            .values()
+            .filter(|session| session.is_closed())
+            .count();
+        self.sessions_lost + closed as u64
+    }
+
     pub fn dump_shards<'a>(&'a self) -> impl Iterator<Item = &'a str> {
         self.cache.iter().filter_map(|(shard_id, entry)| {
             if entry.data.lock().unwrap().is_some() {
@@ -143,52 +935,110 @@ impl<R> Lock<R> {
     }
 }
 
-impl CacheEntry {
-    async fn release(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let data = match self.data.lock().unwrap().take() {
-            Some(data) => data,
-            None => return Ok(()),
-        };
-        self.request_tx
-            .send(Ok(LockRequest {
-                body: Some(lock_request::Body::Released(data)),
-            }))
-            .await?;
-        Ok(())
+impl LockError {
+    fn from_status(status: Status, shard_ids: &[String]) -> Self {
+        let shard_ids = shard_ids.to_vec();
+        match status.code() {
+            Code::ResourceExhausted => LockError::WouldBlock { shard_ids },
+            Code::DeadlineExceeded => LockError::TimedOut { shard_ids },
+            Code::Aborted => LockError::Deadlock { shard_ids },
+            Code::NotFound => LockError::ShardNotFound { shard_ids },
+            _ => LockError::Status(status),
+        }
     }
 }
 
-async fn handle_release(
-    entry: CacheEntry,
-    response_rx: impl Stream<Item = Result<LockResponse, Status>>,
-) {
-    if let Err(err) = handle_release_inner(entry, response_rx).await {
-        log::error!("Handle release failed: {}", err);
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LockError::AlreadyLocked(key) => write!(f, "key {} is already locked", key),
+            LockError::StillLocked(key) => write!(f, "key {} is still locked", key),
+            LockError::KeyNotFound(key) => write!(f, "key {} not found", key),
+            LockError::ShardNotFound { shard_ids } => {
+                write!(f, "shards {} not found", shard_ids.join(", "))
+            }
+            LockError::NotLocked(key) => write!(f, "key {} is not locked", key),
+            LockError::NotOwner { key, owner } => {
+                write!(f, "key {} is locked by {}", key, owner)
+            }
+            LockError::WouldBlock { shard_ids } => {
+                write!(
+                    f,
+                    "shards {} are held by another client",
+                    shard_ids.join(", ")
+                )
+            }
+            LockError::TimedOut { shard_ids } => {
+                write!(f, "timed out acquiring shards {}", shard_ids.join(", "))
+            }
+            LockError::Deadlock { shard_ids } => write!(
+                f,
+                "deadlock detected acquiring shards {}",
+                shard_ids.join(", ")
+            ),
+            LockError::TooManyAttempts => write!(f, "too many attempts"),
+            LockError::Disconnected => write!(f, "connection to the server closed"),
+            LockError::Status(status) => write!(f, "{}", status),
+            LockError::Metrics(err) => write!(f, "failed to write metrics: {}", err),
+        }
+    }
+}
+
+impl error::Error for LockError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            LockError::Status(status) => Some(status),
+            LockError::Metrics(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<Status> for LockError {
+    fn from(status: Status) -> Self {
+        LockError::Status(status)
+    }
+}
+
+impl From<csv::Error> for LockError {
+    fn from(err: csv::Error) -> Self {
+        LockError::Metrics(err)
+    }
+}
+
+impl From<mpsc::SendError> for LockError {
+    fn from(_: mpsc::SendError) -> Self {
+        LockError::Disconnected
+    }
+}
+
+impl LockGuard {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        if let Some(data) = self.data.lock().unwrap().as_mut() {
+            // The key may have been removed since it was locked.
+            if let Some(lock) = data.locks.get_mut(&self.key) {
+                if lock.is_locked() && lock.owner == self.owner {
+                    lock.unlock();
+                }
+            }
+            return;
+        }
+        let key = replace(&mut self.key, String::new());
+        self.pending_unlocks.lock().unwrap().push(key);
     }
 }
 
-/// Waits for the server to send a `Release` message on `response_rx` and then releases
-/// the cached shard.
-async fn handle_release_inner(
-    entry: CacheEntry,
-    response_rx: impl Stream<Item = Result<LockResponse, Status>>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    futures::pin_mut!(entry);
-    futures::pin_mut!(response_rx);
-
-    let response = match response_rx.next().await {
-        Some(response) => response?,
-        None => return Err("lock not released".into()),
-    };
-    let shard_id = response.expect_release()?;
-    log::warn!("shard {} stolen", shard_id);
-
-    log::info!("sending released request for shard {}", shard_id);
-    entry.release().await?;
-
-    if let Some(res) = response_rx.next().await {
-        log::error!("unexpected message {:?}", res);
-        return Err("connection closed".into());
-    }
-    Ok(())
+/// Whether an error may be because our routes are out of date, after the shard a key was in
+/// was split or merged.
+fn is_misrouted(err: &LockError) -> bool {
+    match err {
+        LockError::KeyNotFound(_) | LockError::ShardNotFound { .. } => true,
+        _ => false,
+    }
 }
@@ -1,19 +1,22 @@
 mod lock;
 mod logger;
+mod router;
+mod session;
 mod ui;
 
 use std::io;
-use std::sync::Arc;
 use std::time::Duration;
 
 use futures::StreamExt;
+use rand::Rng;
 use structopt::StructOpt;
 use tokio::net::signal;
 use tokio::runtime::Runtime;
 
-use crate::lock::Lock;
+use crate::lock::{Lock, LockError};
+use crate::router::Router;
 use crate::ui::Ui;
-use shardik::api::*;
+use shardik::api::Wait;
 use shardik::metrics::{Metrics, MetricsOpts};
 use shardik::resource::{FileSystem, Resource};
 
@@ -25,6 +28,10 @@ struct Opts {
     /// The service endpoint to use.
     #[structopt(long, default_value = "http://[::1]:10000")]
     endpoint: http::Uri,
+    /// The endpoint of a server shards are partitioned across. Shards are assigned to nodes
+    /// using a consistent hash ring, which should match the servers'.
+    #[structopt(long = "node")]
+    nodes: Vec<String>,
     #[structopt(flatten)]
     fs: FileSystem,
     #[structopt(flatten)]
@@ -32,6 +39,10 @@ struct Opts {
     /// The name of the client.
     #[structopt(long)]
     client_name: Option<String>,
+    /// The priority to request shards with. Higher priority clients are granted shards
+    /// first.
+    #[structopt(long, default_value = "0")]
+    priority: u32,
     /// The initial key to lock.
     #[structopt(long, default_value = "0/0")]
     initial_key: String,
@@ -44,6 +55,34 @@ struct Opts {
     /// The probability of switching shards when perturbing the key.
     #[structopt(long, default_value = "0.1")]
     perturb_shard_chance: f64,
+    /// The probability of only checking whether a key is locked, which needs the shard in
+    /// shared mode, rather than locking it.
+    #[structopt(long, default_value = "0")]
+    read_chance: f64,
+    /// How long to wait for a shard held by another client in milliseconds before giving up
+    /// on the key. Zero means give up straight away. If not set, wait for as long as it takes.
+    #[structopt(long)]
+    acquire_timeout: Option<u64>,
+    /// The number of keys to lock together. Each key after the first is a perturbation of
+    /// the one before.
+    #[structopt(long, default_value = "1")]
+    keys_per_lock: usize,
+    /// Whether to wait for keys locked by other clients to be unlocked, rather than giving
+    /// up on them.
+    #[structopt(long)]
+    wait_for_key: bool,
+    /// How long to wait for a key to be unlocked in milliseconds, when waiting for keys. If
+    /// not set, wait for as long as it takes.
+    #[structopt(long)]
+    key_timeout: Option<u64>,
+    /// How long key locks last in milliseconds once the server has their shard back, after
+    /// which the server clears them. If not set, keys stay locked until they are unlocked.
+    #[structopt(long)]
+    key_ttl: Option<u64>,
+    /// Release shards given up on the way to acquiring others straight away, rather than
+    /// along with the next acquire request.
+    #[structopt(long)]
+    no_piggyback_releases: bool,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -62,12 +101,30 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         terminal.hide_cursor()?;
         let mut ui = Ui::new();
 
-        let resource = Arc::new(opts.fs);
+        let resource = opts.fs;
         let metrics = Metrics::new(opts.metrics)?;
-        let client = client::LockServiceClient::connect(opts.endpoint)?;
-        let mut lock = Lock::new(opts.client_name, client, resource.clone(), metrics);
+        let router = Router::new(&opts.endpoint, opts.nodes);
+        let mut lock = Lock::new(
+            opts.client_name,
+            router,
+            opts.priority,
+            opts.key_ttl.map(Duration::from_millis),
+            !opts.no_piggyback_releases,
+            metrics,
+        );
+        if let Err(err) = lock.discover_leader().await {
+            log::warn!("Failed to discover cluster leader: {}", err);
+        }
+        // Keys are looked up in the routes, so it's no use carrying on without them.
+        lock.refresh_routes().await?;
 
         let mut key = opts.initial_key;
+        let wait = match opts.acquire_timeout {
+            None => Wait::Forever,
+            Some(0) => Wait::Never,
+            Some(timeout) => Wait::For(Duration::from_millis(timeout)),
+        };
+        let key_timeout = opts.key_timeout.map(Duration::from_millis);
 
         let mut i = 0u64;
         let ctrl_c = signal::ctrl_c()?;
@@ -82,24 +139,71 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 break;
             }
 
-            log::info!("Trying to lock key {}", key);
-            if opts.tui {
-                ui.draw(&mut terminal, &lock)?;
-            }
-
-            if lock.lock(&key).await? {
-                log::info!("Lock acquired on key {}", key);
-                resource
-                    .access(&key, Duration::from_millis(opts.access_duration))
-                    .await?;
-                log::info!("Unlocking key {}", key);
+            if rand::thread_rng().gen_bool(opts.read_chance) {
+                log::info!("Checking key {}", key);
+                let locked = lock.is_locked(&key).await?;
+                log::info!(
+                    "Key {} is {}",
+                    key,
+                    if locked { "locked" } else { "unlocked" }
+                );
+            } else if opts.keys_per_lock > 1 {
+                let mut keys = vec![key.clone()];
+                while keys.len() < opts.keys_per_lock {
+                    let next =
+                        resource.perturb_key(keys.last().unwrap(), opts.perturb_shard_chance);
+                    keys.push(next);
+                }
+                let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+                log::info!("Trying to lock keys {:?}", keys);
                 if opts.tui {
                     ui.draw(&mut terminal, &lock)?;
                 }
-                lock.unlock(&key).await?;
-                log::info!("Lock released on key {}", key);
+
+                let locked = lock.lock_many(&keys).await?;
+                for (key, _) in keys.iter().zip(locked).filter(|&(_, locked)| locked) {
+                    log::info!("Lock acquired on key {}", key);
+                    access(&resource, &lock, key, opts.access_duration).await?;
+                    lock.unlock(key).await?;
+                    log::info!("Lock released on key {}", key);
+                }
             } else {
-                log::info!("Failed to lock key {}", key);
+                log::info!("Trying to lock key {}", key);
+                if opts.tui {
+                    ui.draw(&mut terminal, &lock)?;
+                }
+
+                let result = match wait {
+                    _ if opts.wait_for_key => lock.wait_lock(&key, key_timeout).await,
+                    Wait::Never => lock.try_lock(&key).await,
+                    wait => lock.lock_guard(&key, wait).await,
+                };
+                match result {
+                    Ok(guard) => {
+                        log::info!("Lock acquired on key {}", key);
+                        access(&resource, &lock, &key, opts.access_duration).await?;
+                        log::info!("Unlocking key {}", key);
+                        if opts.tui {
+                            ui.draw(&mut terminal, &lock)?;
+                        }
+                        drop(guard);
+                        log::info!("Lock released on key {}", key);
+                    }
+                    Err(LockError::AlreadyLocked(_)) => log::info!("Failed to lock key {}", key),
+                    Err(ref err) if gave_up(err) => {
+                        log::info!("Gave up waiting for key {}: {}", key, err)
+                    }
+                    // Keys and shards can be removed while the client is running.
+                    Err(err @ LockError::KeyNotFound(_))
+                    | Err(err @ LockError::ShardNotFound { .. }) => {
+                        log::warn!("Skipping key {}: {}", key, err)
+                    }
+                    Err(err @ LockError::Deadlock { .. }) => {
+                        log::warn!("{}, releasing all shards", err);
+                        lock.release_all().await;
+                    }
+                    Err(err) => return Err(err.into()),
+                }
             }
             key = resource.perturb_key(&key, opts.perturb_shard_chance);
 
@@ -109,6 +213,12 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
 
         lock.release_all().await;
+        if lock.sessions_lost() != 0 {
+            log::warn!(
+                "Lost {} sessions, any shards held on them were reclaimed",
+                lock.sessions_lost()
+            );
+        }
 
         Result::<(), Box<dyn std::error::Error>>::Ok(())
     })?;
@@ -116,3 +226,41 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     runtime.shutdown_on_idle();
     Ok(())
 }
+
+/// Accesses a locked key with the fencing token of its shard. Losing the shard before or
+/// during the access isn't fatal, since the key will be locked again later.
+async fn access(
+    resource: &impl Resource,
+    lock: &Lock,
+    key: &str,
+    access_duration: u64,
+) -> io::Result<()> {
+    let fence = match lock.fence(key) {
+        Some(fence) => fence,
+        None => {
+            log::warn!("Lost the shard for key {} before accessing it", key);
+            return Ok(());
+        }
+    };
+    match resource
+        .access(key, &fence, Duration::from_millis(access_duration))
+        .await
+    {
+        Err(ref err) if err.kind() == io::ErrorKind::PermissionDenied => {
+            log::warn!("Access to key {} was fenced off: {}", key, err);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Whether locking a key failed because it or its shard was held for longer than we were
+/// willing to wait.
+fn gave_up(err: &LockError) -> bool {
+    match err {
+        LockError::WouldBlock { .. } | LockError::TimedOut { .. } | LockError::StillLocked(_) => {
+            true
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+
+use tonic::transport::Channel;
+
+use shardik::api::*;
+use shardik::ring::Ring;
+
+/// Decides which server to send the requests for each shard to.
+pub struct Router {
+    clients: HashMap<String, client::LockServiceClient<Channel>>,
+    /// Every server endpoint we know of, and the index of the default one, which is the
+    /// leader if the servers form a cluster.
+    endpoints: Vec<String>,
+    endpoint: usize,
+    /// The ring of servers shards are partitioned across, if any.
+    ring: Option<Ring>,
+    /// Shards which a server has redirected us to another node for, because our ring is out
+    /// of date.
+    routes: HashMap<String, String>,
+}
+
+impl Router {
+    pub fn new(endpoint: &http::Uri, nodes: Vec<String>) -> Self {
+        Router {
+            clients: HashMap::new(),
+            endpoints: vec![normalize(&endpoint.to_string())],
+            endpoint: 0,
+            ring: if nodes.is_empty() {
+                None
+            } else {
+                Some(Ring::new(nodes))
+            },
+            routes: HashMap::new(),
+        }
+    }
+
+    pub fn default_endpoint(&self) -> String {
+        self.endpoints[self.endpoint].clone()
+    }
+
+    /// Gets the endpoint of every server responsible for some shards.
+    pub fn servers(&self) -> Vec<String> {
+        match &self.ring {
+            Some(ring) => ring.nodes().iter().map(|node| normalize(node)).collect(),
+            None => vec![self.default_endpoint()],
+        }
+    }
+
+    /// Gets the endpoint of the server responsible for a shard.
+    pub fn endpoint(&self, shard_id: &str) -> String {
+        if let Some(endpoint) = self.routes.get(shard_id) {
+            return endpoint.clone();
+        }
+        match &self.ring {
+            Some(ring) => normalize(ring.node(shard_id)),
+            None => self.default_endpoint(),
+        }
+    }
+
+    /// Gets a client connected to `endpoint`.
+    pub fn client(
+        &mut self,
+        endpoint: &str,
+    ) -> Result<&mut client::LockServiceClient<Channel>, Box<dyn std::error::Error>> {
+        if !self.clients.contains_key(endpoint) {
+            log::info!("Connecting to {}", endpoint);
+            let uri: http::Uri = endpoint.parse()?;
+            let client = client::LockServiceClient::connect(uri)?;
+            self.clients.insert(endpoint.to_owned(), client);
+        }
+        Ok(self.clients.get_mut(endpoint).unwrap())
+    }
+
+    /// Makes `endpoint` the default server.
+    pub fn set_leader(&mut self, endpoint: String) {
+        let endpoint = normalize(&endpoint);
+        self.endpoint = match self.endpoints.iter().position(|e| *e == endpoint) {
+            Some(index) => index,
+            None => {
+                self.endpoints.push(endpoint);
+                self.endpoints.len() - 1
+            }
+        };
+    }
+
+    /// Switches the default server to the next one we know of, for when it is unavailable.
+    pub fn next_endpoint(&mut self) {
+        self.endpoint = (self.endpoint + 1) % self.endpoints.len();
+    }
+
+    pub fn redirect(&mut self, shard_id: &str, redirect: Redirect) {
+        match redirect.reason() {
+            redirect::Reason::NotLeader => self.set_leader(redirect.endpoint),
+            redirect::Reason::WrongNode => {
+                self.routes
+                    .insert(shard_id.to_owned(), normalize(&redirect.endpoint));
+            }
+        }
+    }
+}
+
+fn normalize(endpoint: &str) -> String {
+    endpoint.trim_end_matches('/').to_owned()
+}
@@ -0,0 +1,415 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::mem::replace;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::channel::{mpsc, oneshot};
+use futures::{SinkExt, Stream, StreamExt};
+use tokio::timer;
+use tonic::transport::Channel;
+use tonic::{Code, Request, Status};
+
+use shardik::api::*;
+
+/// A stream to a server which carries our requests for every shard we get from it.
+///
+/// The first response to each request goes to whoever made it. Once a request has been
+/// granted its shards, a background task releases them from the cache whenever the server
+/// asks. Another task sends heartbeats, which keep the session alive and renew the leases
+/// on every shard held on it. If the server stops hearing from us, it ends the session and
+/// hands our shards on.
+#[derive(Clone)]
+pub struct Session {
+    id: u64,
+    request_tx: mpsc::Sender<Result<SessionRequest, Status>>,
+    state: Arc<Mutex<State>>,
+}
+
+/// Represents cached shard data. If `data` is Some then it is cached. If it is none then
+/// it has been recently stolen by another client.
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    pub shard_id: String,
+    pub data: Arc<Mutex<Option<ShardData>>>,
+    /// Whether the shard is held exclusively, and so may be modified.
+    pub mode: Mode,
+    /// The data as it was granted, which changes are released as a delta from.
+    base: Arc<ShardData>,
+    /// Whether the shard was granted along with others by the same request, so messages
+    /// about it need to say which shard they are for.
+    tagged: bool,
+    session_id: u64,
+    request_id: u64,
+    request_tx: mpsc::Sender<Result<SessionRequest, Status>>,
+}
+
+#[derive(Default)]
+struct State {
+    next_request_id: u64,
+    /// Requests waiting for their first response.
+    pending: HashMap<u64, oneshot::Sender<Result<LockResponse, Status>>>,
+    /// Requests which have been granted their shards.
+    held: HashMap<u64, Held>,
+    closed: bool,
+}
+
+/// The shards granted to a request.
+#[derive(Default)]
+struct Held {
+    entries: HashMap<String, CacheEntry>,
+    /// Shards the server asked us to release before they were cached.
+    released: Vec<String>,
+}
+
+impl Session {
+    /// Opens and registers a session with the server `client` is connected to.
+    pub async fn open(
+        client: &mut client::LockServiceClient<Channel>,
+        client_id: String,
+    ) -> Result<Self, Status> {
+        let (mut request_tx, request_rx) = mpsc::channel(0);
+        let mut response_rx = client.session(Request::new(request_rx)).await?.into_inner();
+
+        request_tx
+            .send(Ok(SessionRequest {
+                register: Some(Register { client_id }),
+                ..SessionRequest::default()
+            }))
+            .await
+            .map_err(|err| Status::new(Code::Unavailable, err.to_string()))?;
+        let registered = match response_rx.next().await {
+            Some(Ok(SessionResponse {
+                body: Some(session_response::Body::Registered(registered)),
+                ..
+            })) => registered,
+            Some(Ok(_)) => {
+                return Err(Status::new(
+                    Code::Internal,
+                    "expected response to be `registered`",
+                ))
+            }
+            Some(Err(status)) => return Err(status),
+            None => return Err(Status::new(Code::Unavailable, "connection closed")),
+        };
+        log::info!("Registered session {}", registered.session_id);
+
+        let session = Session {
+            id: registered.session_id,
+            request_tx,
+            state: Arc::default(),
+        };
+        // A zero interval would make the heartbeat timer panic.
+        let interval = Duration::from_millis(cmp::max(registered.heartbeat_interval_millis, 1));
+        tokio::spawn(session.clone().handle_responses(response_rx));
+        tokio::spawn(heartbeat(session.clone(), interval));
+        Ok(session)
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Whether the session has ended, so a new one is needed.
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    /// Sends a new request, along with releases for earlier requests, and waits for the
+    /// server's first response to it. If the response grants some shards, they must be
+    /// passed to `hold` once cached.
+    pub async fn request(
+        &mut self,
+        request: LockRequest,
+        releases: Vec<ReleasedRequest>,
+    ) -> Result<(u64, LockResponse), Status> {
+        let (response_tx, response_rx) = oneshot::channel();
+        let request_id = {
+            let mut state = self.state.lock().unwrap();
+            let request_id = state.next_request_id;
+            state.next_request_id += 1;
+            state.pending.insert(request_id, response_tx);
+            request_id
+        };
+
+        if self.send(request_id, request, releases).await.is_err() {
+            self.state.lock().unwrap().pending.remove(&request_id);
+            return Err(Status::new(Code::Unavailable, "session closed"));
+        }
+        match response_rx.await {
+            Ok(response) => Ok((request_id, response?)),
+            Err(_) => Err(Status::new(Code::Unavailable, "session closed")),
+        }
+    }
+
+    /// Makes a cache entry for a shard granted to a request.
+    pub fn cache_entry(
+        &self,
+        request_id: u64,
+        shard_id: String,
+        data: ShardData,
+        base: Arc<ShardData>,
+        mode: Mode,
+        tagged: bool,
+    ) -> CacheEntry {
+        CacheEntry {
+            shard_id,
+            data: Arc::new(Mutex::new(Some(data))),
+            mode,
+            base,
+            tagged,
+            session_id: self.id,
+            request_id,
+            request_tx: self.request_tx.clone(),
+        }
+    }
+
+    /// Starts handling release requests for the shards granted to a request, now that they
+    /// are cached.
+    pub async fn hold(
+        &mut self,
+        request_id: u64,
+        entries: HashMap<String, CacheEntry>,
+    ) -> Result<(), mpsc::SendError> {
+        let released = {
+            let mut state = self.state.lock().unwrap();
+            let held = match state.held.get_mut(&request_id) {
+                Some(held) => held,
+                None => {
+                    // The request has already failed, so the server has reclaimed the shards.
+                    for entry in entries.values() {
+                        entry.data.lock().unwrap().take();
+                    }
+                    return Ok(());
+                }
+            };
+            held.entries = entries;
+            let released: Vec<CacheEntry> = replace(&mut held.released, Vec::new())
+                .iter()
+                .filter_map(|shard_id| held.entries.remove(shard_id))
+                .collect();
+            if held.entries.is_empty() {
+                state.held.remove(&request_id);
+            }
+            released
+        };
+
+        for mut entry in released {
+            log::warn!("shard {} stolen", entry.shard_id);
+            entry.release().await?;
+        }
+        Ok(())
+    }
+
+    /// Gives back the shards granted to a request without using them, by ending the request.
+    /// The server hands them on with the data they were granted with.
+    pub async fn give_back(&mut self, request_id: u64) -> Result<(), mpsc::SendError> {
+        self.state.lock().unwrap().held.remove(&request_id);
+        self.end(request_id).await
+    }
+
+    async fn send(
+        &mut self,
+        request_id: u64,
+        request: LockRequest,
+        releases: Vec<ReleasedRequest>,
+    ) -> Result<(), mpsc::SendError> {
+        self.request_tx
+            .send(Ok(SessionRequest {
+                request_id,
+                request: Some(request),
+                releases,
+                ..SessionRequest::default()
+            }))
+            .await
+    }
+
+    async fn end(&mut self, request_id: u64) -> Result<(), mpsc::SendError> {
+        self.request_tx
+            .send(Ok(SessionRequest {
+                request_id,
+                end: true,
+                ..SessionRequest::default()
+            }))
+            .await
+    }
+
+    async fn handle_responses(
+        mut self,
+        response_rx: impl Stream<Item = Result<SessionResponse, Status>>,
+    ) {
+        futures::pin_mut!(response_rx);
+        while let Some(response) = response_rx.next().await {
+            let result = match response {
+                Ok(response) => self.handle_response(response).await,
+                Err(status) => Err(status.into()),
+            };
+            if let Err(err) = result {
+                log::error!(
+                    "Session {} lost, any shards held on it have been reclaimed: {}",
+                    self.id,
+                    err
+                );
+                break;
+            }
+        }
+
+        // The server reclaims every shard held on the session once it ends, so the cached
+        // data can no longer be trusted.
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.pending.clear();
+        for (_, held) in state.held.drain() {
+            for entry in held.entries.values() {
+                entry.data.lock().unwrap().take();
+            }
+        }
+    }
+
+    async fn handle_response(
+        &mut self,
+        response: SessionResponse,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let request_id = response.request_id;
+        let result = match response.body {
+            Some(session_response::Body::Response(response)) => Ok(response),
+            Some(session_response::Body::Error(error)) => Err(error.into_status()),
+            Some(session_response::Body::Registered(_)) => {
+                return Err("session registered twice".into())
+            }
+            None => return Ok(()),
+        };
+
+        let pending = self.state.lock().unwrap().pending.remove(&request_id);
+        if let Some(response_tx) = pending {
+            let granted = match &result {
+                Ok(LockResponse {
+                    body: Some(lock_response::Body::Acquired(_)),
+                })
+                | Ok(LockResponse {
+                    body: Some(lock_response::Body::AcquiredMany(_)),
+                }) => true,
+                _ => false,
+            };
+            if granted {
+                self.state
+                    .lock()
+                    .unwrap()
+                    .held
+                    .insert(request_id, Held::default());
+            }
+            if response_tx.send(result).is_err() && granted {
+                // Nobody wants the shards any more, so give them back.
+                self.give_back(request_id).await?;
+            }
+            return Ok(());
+        }
+
+        let shard_id = match result {
+            Ok(response) => response.expect_release()?,
+            Err(status) => {
+                log::error!("Request {} failed: {}", request_id, status);
+                // The server may have reclaimed the shards (e.g. because the lease expired),
+                // so the cached data can no longer be trusted.
+                let held = self.state.lock().unwrap().held.remove(&request_id);
+                for entry in held.iter().flat_map(|held| held.entries.values()) {
+                    entry.data.lock().unwrap().take();
+                }
+                return Ok(());
+            }
+        };
+        let entry = {
+            let mut state = self.state.lock().unwrap();
+            let held = state
+                .held
+                .get_mut(&request_id)
+                .ok_or_else(|| format!("unexpected release of shard {}", shard_id))?;
+            let entry = held.entries.remove(&shard_id);
+            if entry.is_none() {
+                held.released.push(shard_id.clone());
+            } else if held.entries.is_empty() {
+                state.held.remove(&request_id);
+            }
+            entry
+        };
+        if let Some(mut entry) = entry {
+            log::warn!("shard {} stolen", shard_id);
+            log::info!("sending released request for shard {}", shard_id);
+            entry.release().await?;
+        }
+        Ok(())
+    }
+}
+
+impl CacheEntry {
+    /// The id of the session the shard was granted on.
+    pub fn session_id(&self) -> u64 {
+        self.session_id
+    }
+
+    pub async fn release(&mut self) -> Result<(), mpsc::SendError> {
+        let release = match self.take_release() {
+            Some(release) => release,
+            None => return Ok(()),
+        };
+        self.request_tx
+            .send(Ok(SessionRequest {
+                request_id: release.request_id,
+                request: release.request,
+                ..SessionRequest::default()
+            }))
+            .await
+    }
+
+    /// Takes the data out of the cache, and makes the message releasing it, to be sent along
+    /// with another request on the same session. Returns `None` if it was already released.
+    pub fn take_release(&mut self) -> Option<ReleasedRequest> {
+        let data = match self.data.lock().unwrap().take()? {
+            // The server ignores the data for shared holders, so don't bother sending it.
+            _ if self.mode == Mode::Shared => ShardData::default(),
+            data => data.delta_from(&self.base),
+        };
+        let body = if self.tagged {
+            lock_request::Body::ReleasedShard(ReleasedShard {
+                shard_id: self.shard_id.clone(),
+                data: Some(data),
+            })
+        } else {
+            lock_request::Body::Released(data)
+        };
+        Some(ReleasedRequest {
+            request_id: self.request_id,
+            request: Some(LockRequest { body: Some(body) }),
+        })
+    }
+}
+
+/// Sends heartbeats every `interval` for as long as the session is open.
+async fn heartbeat(mut session: Session, interval: Duration) {
+    let mut interval = timer::Interval::new_interval(interval);
+    while interval.next().await.is_some() {
+        {
+            let mut state = session.state.lock().unwrap();
+            if state.closed {
+                return;
+            }
+            // Forget requests whose shards have all been released without being asked.
+            state.held.retain(|_, held| {
+                held.entries.is_empty()
+                    || held
+                        .entries
+                        .values()
+                        .any(|entry| entry.data.lock().unwrap().is_some())
+            });
+        }
+
+        log::debug!("sending heartbeat on session {}", session.id);
+        let heartbeat = SessionRequest {
+            heartbeat: true,
+            ..SessionRequest::default()
+        };
+        if session.request_tx.send(Ok(heartbeat)).await.is_err() {
+            return;
+        }
+    }
+}
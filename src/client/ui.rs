@@ -19,11 +19,7 @@ impl Ui {
         }
     }
 
-    pub fn draw<B: Backend, R>(
-        &mut self,
-        terminal: &mut Terminal<B>,
-        lock: &Lock<R>,
-    ) -> io::Result<()> {
+    pub fn draw<B: Backend>(&mut self, terminal: &mut Terminal<B>, lock: &Lock) -> io::Result<()> {
         terminal.draw(|mut f| {
             let chunks = Layout::default()
                 .direction(Direction::Horizontal)
@@ -34,7 +30,7 @@ impl Ui {
         })
     }
 
-    fn draw_shards<B: Backend, R>(&mut self, frame: &mut Frame<B>, area: Rect, lock: &Lock<R>) {
+    fn draw_shards<B: Backend>(&mut self, frame: &mut Frame<B>, area: Rect, lock: &Lock) {
         let block = Block::default().borders(Borders::ALL).title("Shards");
         List::new(lock.dump_shards().map(Text::raw))
             .block(block)
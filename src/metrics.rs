@@ -21,6 +21,14 @@ pub struct Record<'a> {
     pub client_name: Option<Cow<'a, str>>,
     pub key: Cow<'a, str>,
     pub nanos: u128,
+    /// The priority the shard was requested with. Missing from records written before
+    /// priorities were added.
+    #[serde(default)]
+    pub priority: u32,
+    /// How much of `nanos` was spent waiting for the key to be unlocked by someone else,
+    /// rather than for its shard.
+    #[serde(default)]
+    pub key_wait_nanos: u128,
 }
 
 impl Metrics {
@@ -41,11 +49,15 @@ impl Metrics {
         client_name: &Option<String>,
         key: &str,
         dur: Duration,
+        key_wait: Duration,
+        priority: u32,
     ) -> csv::Result<()> {
         self.writer.serialize(Record {
             key: Cow::Borrowed(key),
             client_name: client_name.as_ref().map(Cow::from),
             nanos: dur.as_nanos(),
+            priority,
+            key_wait_nanos: key_wait.as_nanos(),
         })?;
         self.writer.flush()?;
         Ok(())
@@ -56,6 +68,7 @@ pub fn parse_records(opts: MetricsOpts) -> io::Result<Vec<Record<'static>>> {
     let file = fs::File::open(opts.metrics_file)?;
     let mut reader = csv::ReaderBuilder::new()
         .has_headers(false)
+        .flexible(true)
         .from_reader(file);
     Ok(reader
         .deserialize::<Record>()
@@ -1,3 +1,4 @@
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::PathBuf;
 use std::time::Duration;
 use std::{fs, io};
@@ -10,10 +11,22 @@ use tokio::timer;
 
 #[tonic::async_trait]
 pub trait Resource {
+    /// Gets every key along with the id of the shard it starts out in.
     fn keys(&self) -> Vec<(String, String)>;
-    fn get_shard_id(&self, key: &str) -> String;
     fn perturb_key(&self, key: &str, perturb_shard_chance: f64) -> String;
-    async fn access(&self, key: &str, access_duration: Duration) -> io::Result<()>;
+    /// Accesses a key while holding its shard. Fails with `PermissionDenied` if the fencing
+    /// token is older than one the key has already been accessed with, since then the key's
+    /// shard has been handed to someone else.
+    async fn access(&self, key: &str, fence: &Fence, access_duration: Duration) -> io::Result<()>;
+}
+
+/// The fencing token a shard was granted with, which proves to a resource that the client
+/// accessing one of its keys still holds the shard. Tokens increase across all shards, so they
+/// stay valid for a key when it moves between shards.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fence {
+    pub shard_id: String,
+    pub token: u64,
 }
 
 #[derive(StructOpt)]
@@ -47,10 +60,6 @@ impl Resource for FileSystem {
         keys
     }
 
-    fn get_shard_id(&self, key: &str) -> String {
-        parse_key(key).0.to_string()
-    }
-
     fn perturb_key(&self, key: &str, perturb_shard_chance: f64) -> String {
         let (mut shard_id, mut item_id) = parse_key(key);
 
@@ -64,7 +73,8 @@ impl Resource for FileSystem {
         format_key(shard_id, item_id)
     }
 
-    async fn access(&self, key: &str, access_duration: Duration) -> io::Result<()> {
+    async fn access(&self, key: &str, fence: &Fence, access_duration: Duration) -> io::Result<()> {
+        self.check_fence(key, fence)?;
         let path = self.base_path.join(key);
         let file = fs::File::open(path)?;
         file.try_lock_exclusive()?;
@@ -74,6 +84,52 @@ impl Resource for FileSystem {
     }
 }
 
+impl FileSystem {
+    /// Checks a fencing token against the latest one the key has been accessed with, which
+    /// is kept in a file per key, and records it if it is newer. The lock service splits and
+    /// merges its shards regardless of the directories here, so keys are the only unit the two
+    /// agree on.
+    fn check_fence(&self, key: &str, fence: &Fence) -> io::Result<()> {
+        let path = self.base_path.join(".fencing").join(key);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)?;
+        file.lock_exclusive()?;
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let latest = match contents.trim() {
+            "" => 0,
+            latest => latest
+                .parse()
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+        };
+        if fence.token < latest {
+            file.unlock()?;
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "fencing token {} from shard {} is stale for key {}, it has been accessed \
+                     with {}",
+                    fence.token, fence.shard_id, key, latest
+                ),
+            ));
+        }
+        if fence.token > latest {
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            write!(file, "{}", fence.token)?;
+        }
+        file.unlock()?;
+        Ok(())
+    }
+}
+
 fn format_key(shard_id: u32, item_id: u32) -> String {
     format!("{}/{}", shard_id, item_id)
 }
@@ -0,0 +1,48 @@
+use std::collections::BTreeMap;
+
+/// The number of points each node is given on the ring, to spread shards evenly.
+const POINTS_PER_NODE: u32 = 64;
+
+/// A consistent hash ring, assigning each shard id to one of a set of nodes.
+#[derive(Debug, Clone)]
+pub struct Ring {
+    points: BTreeMap<u64, usize>,
+    nodes: Vec<String>,
+}
+
+impl Ring {
+    pub fn new(nodes: Vec<String>) -> Self {
+        assert!(!nodes.is_empty(), "ring must have at least one node");
+
+        let mut points = BTreeMap::new();
+        for (index, node) in nodes.iter().enumerate() {
+            for point in 0..POINTS_PER_NODE {
+                points.insert(hash(&format!("{}#{}", node, point)), index);
+            }
+        }
+        Ring { points, nodes }
+    }
+
+    pub fn nodes(&self) -> &[String] {
+        &self.nodes
+    }
+
+    /// Gets the node which owns the given shard.
+    pub fn node(&self, shard_id: &str) -> &str {
+        let hash = hash(shard_id);
+        let (_, &index) = self
+            .points
+            .range(hash..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .unwrap();
+        &self.nodes[index]
+    }
+}
+
+/// 64-bit FNV-1a, used because it is stable across processes and platforms.
+fn hash(value: &str) -> u64 {
+    value.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
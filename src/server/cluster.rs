@@ -0,0 +1,712 @@
+//! Replication of shard data between servers using the Raft consensus algorithm.
+//!
+//! Each server writes its term, vote and log to disk before answering its peers, so that it
+//! keeps its promises to them when it restarts. Without a data directory they are only kept
+//! in memory, and a server which restarts rejoins the cluster as an empty follower.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{cmp, io, mem};
+
+use futures::channel::oneshot;
+use futures::future::join_all;
+use futures::{Future, StreamExt};
+use rand::Rng;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::timer;
+use tonic::transport::Channel;
+use tonic::{Code, Request, Response, Status};
+
+use crate::store::{self, RaftLog, RaftStore, RaftWrite};
+use shardik::api::*;
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(50);
+const ELECTION_TIMEOUT_MILLIS: (u64, u64) = (300, 600);
+const RPC_TIMEOUT: Duration = Duration::from_millis(200);
+const PROPOSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// The number of log entries to keep before compacting them into a snapshot.
+const COMPACT_THRESHOLD: usize = 1024;
+
+pub struct Cluster {
+    /// The endpoint clients use to connect to this server, which also identifies it to its
+    /// peers.
+    id: String,
+    peers: Vec<Peer>,
+    state: Mutex<State>,
+}
+
+pub enum Leadership {
+    /// This server is the leader for the given term, and has applied every entry committed
+    /// in earlier terms.
+    Leader(u64),
+    /// Another server is the leader, if one is known.
+    Follower(Option<String>),
+}
+
+#[derive(Clone)]
+pub struct ClusterService {
+    cluster: Arc<Cluster>,
+}
+
+struct Peer {
+    uri: http::Uri,
+    client: AsyncMutex<client::ClusterServiceClient<Channel>>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+struct State {
+    role: Role,
+    term: u64,
+    voted_for: Option<String>,
+    leader: Option<String>,
+    election_deadline: Instant,
+    /// Entries after the snapshot, so `log[i]` has index `snapshot_index + 1 + i`.
+    log: Vec<LogEntry>,
+    snapshot_index: u64,
+    snapshot_term: u64,
+    snapshot: HashMap<String, ShardData>,
+    commit_index: u64,
+    /// The data for each shard, with every committed entry applied.
+    shards: HashMap<String, ShardData>,
+    /// The index of the first entry of the current term, if this server is the leader.
+    term_start: u64,
+    /// The last entry of the current term known to be on disk, if this server is the leader.
+    /// Only entries on disk count towards a majority.
+    persisted_index: u64,
+    next_index: Vec<u64>,
+    match_index: Vec<u64>,
+    in_flight: Vec<bool>,
+    /// Proposals waiting for their entry to be committed.
+    pending: Vec<(u64, oneshot::Sender<Result<(), Status>>)>,
+    store: RaftStore,
+    /// Changes which haven't been passed to the store yet.
+    unwritten: Vec<RaftWrite>,
+}
+
+impl Cluster {
+    pub fn new(
+        id: String,
+        peers: Vec<http::Uri>,
+        store: RaftStore,
+        saved: RaftLog,
+    ) -> Result<Self, tonic::transport::Error> {
+        let peers = peers
+            .into_iter()
+            .map(|uri| {
+                let client = client::ClusterServiceClient::connect(uri.clone())?;
+                Ok(Peer {
+                    uri,
+                    client: AsyncMutex::new(client),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let peer_count = peers.len();
+        let RaftLog {
+            state,
+            snapshot,
+            entries,
+        } = saved;
+        let voted_for = if state.voted_for.is_empty() {
+            None
+        } else {
+            Some(state.voted_for)
+        };
+
+        // Entries after the snapshot may or may not have been committed, which the leader
+        // will tell us.
+        Ok(Cluster {
+            id,
+            peers,
+            state: Mutex::new(State {
+                role: Role::Follower,
+                term: state.term,
+                voted_for,
+                leader: None,
+                election_deadline: election_deadline(),
+                log: entries,
+                snapshot_index: snapshot.index,
+                snapshot_term: snapshot.term,
+                snapshot: snapshot.shards.clone(),
+                commit_index: snapshot.index,
+                shards: snapshot.shards,
+                term_start: 0,
+                persisted_index: 0,
+                next_index: vec![1; peer_count],
+                match_index: vec![0; peer_count],
+                in_flight: vec![false; peer_count],
+                pending: Vec::new(),
+                store,
+                unwritten: Vec::new(),
+            }),
+        })
+    }
+
+    pub fn leadership(&self) -> Leadership {
+        let state = self.state.lock().unwrap();
+        match state.role {
+            Role::Leader if state.commit_index >= state.term_start => {
+                Leadership::Leader(state.term)
+            }
+            // Entries from earlier terms may still be uncommitted.
+            Role::Leader => Leadership::Follower(None),
+            _ => Leadership::Follower(state.leader.clone()),
+        }
+    }
+
+    /// Returns the endpoint of the leader, if known.
+    pub fn leader(&self) -> Option<String> {
+        self.state.lock().unwrap().leader.clone()
+    }
+
+    /// Returns the committed data for each shard.
+    pub fn shards(&self) -> HashMap<String, ShardData> {
+        self.state.lock().unwrap().shards.clone()
+    }
+
+    /// Replicates an entry with the latest data for some shards, waiting until it has been
+    /// committed. Fails if this server is no longer the leader for `term`.
+    pub async fn propose(self: Arc<Self>, term: u64, entry: StoreEntry) -> Result<(), Status> {
+        let committed = {
+            let mut state = self.state.lock().unwrap();
+            if state.role != Role::Leader || state.term != term {
+                return Err(Status::new(Code::Unavailable, "no longer the leader"));
+            }
+
+            state.push(LogEntry {
+                term,
+                entry: Some(entry),
+            });
+            let index = state.last_index();
+            let (committed_tx, committed_rx) = oneshot::channel();
+            state.pending.push((index, committed_tx));
+            tokio::spawn(self.clone().persisted(term, index, state.flush()));
+            committed_rx
+        };
+
+        // Replicate straight away rather than waiting for the next heartbeat.
+        for peer in 0..self.peers.len() {
+            tokio::spawn(self.clone().replicate(peer));
+        }
+
+        match timer::Timeout::new(committed, PROPOSE_TIMEOUT).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(Status::new(Code::Unavailable, "no longer the leader")),
+            Err(_) => Err(Status::new(
+                Code::Unavailable,
+                "timed out replicating entry",
+            )),
+        }
+    }
+
+    /// Drives elections and heartbeats until the process exits.
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = timer::Interval::new_interval(HEARTBEAT_INTERVAL);
+        while interval.next().await.is_some() {
+            let (is_leader, election_due) = {
+                let state = self.state.lock().unwrap();
+                (
+                    state.role == Role::Leader,
+                    Instant::now() >= state.election_deadline,
+                )
+            };
+
+            if is_leader {
+                for peer in 0..self.peers.len() {
+                    tokio::spawn(self.clone().replicate(peer));
+                }
+            } else if election_due {
+                tokio::spawn(self.clone().elect());
+            }
+        }
+    }
+
+    async fn elect(self: Arc<Self>) {
+        let (request, written) = {
+            let mut state = self.state.lock().unwrap();
+            state.role = Role::Candidate;
+            state.term += 1;
+            state.voted_for = Some(self.id.clone());
+            state.leader = None;
+            state.election_deadline = election_deadline();
+            state.save_vote();
+            let request = VoteRequest {
+                term: state.term,
+                candidate_id: self.id.clone(),
+                last_log_index: state.last_index(),
+                last_log_term: state.last_term(),
+            };
+            (request, state.flush())
+        };
+        if let Err(err) = written.await {
+            log::error!("Failed to save vote for term {}: {}", request.term, err);
+            return;
+        }
+        log::info!("Starting election for term {}", request.term);
+
+        let responses = join_all(
+            self.peers
+                .iter()
+                .map(|peer| peer.request_vote(request.clone())),
+        )
+        .await;
+
+        let mut state = self.state.lock().unwrap();
+        let mut votes = 1;
+        for (peer, response) in self.peers.iter().zip(responses) {
+            match response {
+                Ok(response) if response.term > state.term => {
+                    state.step_down(response.term);
+                    let _ = state.flush();
+                    return;
+                }
+                Ok(response) if response.vote_granted => votes += 1,
+                Ok(_) => (),
+                Err(err) => log::debug!("Vote request to {} failed: {}", peer.uri, err),
+            }
+        }
+
+        if state.role == Role::Candidate
+            && state.term == request.term
+            && votes * 2 > self.peers.len() + 1
+        {
+            self.become_leader(&mut state);
+        }
+    }
+
+    fn become_leader(self: &Arc<Self>, state: &mut State) {
+        log::info!("Elected leader for term {}", state.term);
+        state.role = Role::Leader;
+        state.leader = Some(self.id.clone());
+        let next_index = state.last_index() + 1;
+        state.next_index = vec![next_index; self.peers.len()];
+        state.match_index = vec![0; self.peers.len()];
+        state.persisted_index = 0;
+
+        // Entries from earlier terms can only be committed along with one from this term.
+        let term = state.term;
+        state.push(LogEntry { term, entry: None });
+        state.term_start = state.last_index();
+        let index = state.last_index();
+        tokio::spawn(self.clone().persisted(term, index, state.flush()));
+    }
+
+    /// Counts this server's log up to `index` towards committing entries once it is on disk,
+    /// or steps down if it couldn't be written.
+    async fn persisted(
+        self: Arc<Self>,
+        term: u64,
+        index: u64,
+        written: impl Future<Output = io::Result<()>>,
+    ) {
+        let result = written.await;
+        let mut state = self.state.lock().unwrap();
+        if state.role != Role::Leader || state.term != term {
+            return;
+        }
+        match result {
+            Ok(()) => {
+                state.persisted_index = cmp::max(state.persisted_index, index);
+                state.advance_commit();
+            }
+            Err(err) => {
+                log::error!("Failed to write log: {}", err);
+                state.step_down(term);
+            }
+        }
+        let _ = state.flush();
+    }
+
+    /// Sends any entries the peer is missing, or an empty heartbeat if it is up to date.
+    async fn replicate(self: Arc<Self>, peer: usize) {
+        let request = {
+            let mut state = self.state.lock().unwrap();
+            if state.role != Role::Leader || state.in_flight[peer] {
+                return;
+            }
+            state.in_flight[peer] = true;
+            state.append_request(&self.id, peer)
+        };
+
+        let response = self.peers[peer].append_entries(request.clone()).await;
+
+        let mut state = self.state.lock().unwrap();
+        state.in_flight[peer] = false;
+        let response = match response {
+            Ok(response) => response,
+            Err(err) => {
+                log::debug!("Append request to {} failed: {}", self.peers[peer].uri, err);
+                return;
+            }
+        };
+
+        if response.term > state.term {
+            state.step_down(response.term);
+        } else if state.role != Role::Leader || state.term != request.term {
+            // The response is from an earlier term.
+        } else if response.success {
+            let matched = request.prev_log_index + request.entries.len() as u64;
+            state.match_index[peer] = cmp::max(state.match_index[peer], matched);
+            state.next_index[peer] = state.match_index[peer] + 1;
+            state.advance_commit();
+        } else {
+            // The peer's log doesn't match ours, so back up and try an earlier entry.
+            state.next_index[peer] = cmp::max(
+                1,
+                cmp::min(state.next_index[peer] - 1, response.last_log_index + 1),
+            );
+        }
+        let _ = state.flush();
+    }
+}
+
+impl ClusterService {
+    pub fn new(cluster: Arc<Cluster>) -> Self {
+        ClusterService { cluster }
+    }
+}
+
+#[tonic::async_trait]
+impl server::ClusterService for ClusterService {
+    async fn request_vote(
+        &self,
+        request: Request<VoteRequest>,
+    ) -> Result<Response<VoteResponse>, Status> {
+        let (response, written) = {
+            let mut state = self.cluster.state.lock().unwrap();
+            let response = state.request_vote(request.into_inner());
+            (response, state.flush())
+        };
+        written.await.map_err(write_failed)?;
+        Ok(Response::new(response))
+    }
+
+    async fn append_entries(
+        &self,
+        request: Request<AppendRequest>,
+    ) -> Result<Response<AppendResponse>, Status> {
+        let (response, written) = {
+            let mut state = self.cluster.state.lock().unwrap();
+            let response = state.append_entries(request.into_inner());
+            (response, state.flush())
+        };
+        // Even if nothing changed, the entries may have been added by an earlier request
+        // which is still being written.
+        written.await.map_err(write_failed)?;
+        Ok(Response::new(response))
+    }
+}
+
+impl Peer {
+    async fn request_vote(&self, request: VoteRequest) -> Result<VoteResponse, Status> {
+        let mut client = self.client.lock().await;
+        match timer::Timeout::new(client.request_vote(Request::new(request)), RPC_TIMEOUT).await {
+            Ok(response) => Ok(response?.into_inner()),
+            Err(_) => Err(Status::new(Code::DeadlineExceeded, "request timed out")),
+        }
+    }
+
+    async fn append_entries(&self, request: AppendRequest) -> Result<AppendResponse, Status> {
+        let mut client = self.client.lock().await;
+        match timer::Timeout::new(client.append_entries(Request::new(request)), RPC_TIMEOUT).await {
+            Ok(response) => Ok(response?.into_inner()),
+            Err(_) => Err(Status::new(Code::DeadlineExceeded, "request timed out")),
+        }
+    }
+}
+
+impl State {
+    fn request_vote(&mut self, request: VoteRequest) -> VoteResponse {
+        if request.term > self.term {
+            self.step_down(request.term);
+        }
+
+        let up_to_date = (request.last_log_term, request.last_log_index)
+            >= (self.last_term(), self.last_index());
+        let vote_granted = request.term == self.term
+            && up_to_date
+            && self
+                .voted_for
+                .as_ref()
+                .map_or(true, |id| *id == request.candidate_id);
+        if vote_granted {
+            log::info!(
+                "Voting for {} in term {}",
+                request.candidate_id,
+                request.term
+            );
+            self.voted_for = Some(request.candidate_id);
+            self.election_deadline = election_deadline();
+            self.save_vote();
+        }
+
+        VoteResponse {
+            term: self.term,
+            vote_granted,
+        }
+    }
+
+    fn append_entries(&mut self, request: AppendRequest) -> AppendResponse {
+        if request.term < self.term {
+            return self.append_response(false);
+        }
+        if request.term > self.term || self.role != Role::Follower {
+            self.step_down(request.term);
+        }
+        if self.leader.as_ref() != Some(&request.leader_id) {
+            log::info!(
+                "Following leader {} in term {}",
+                request.leader_id,
+                request.term
+            );
+            self.leader = Some(request.leader_id.clone());
+        }
+        self.election_deadline = election_deadline();
+
+        if let Some(snapshot) = request.snapshot {
+            if request.prev_log_index > self.commit_index {
+                self.install_snapshot(
+                    request.prev_log_index,
+                    request.prev_log_term,
+                    snapshot.shards,
+                );
+            }
+            return self.append_response(true);
+        }
+
+        match self.term_at(request.prev_log_index) {
+            Some(term) if term == request.prev_log_term => (),
+            // The entry has already been committed and compacted.
+            None if request.prev_log_index < self.snapshot_index => (),
+            _ => return self.append_response(false),
+        }
+
+        let mut index = request.prev_log_index;
+        for entry in request.entries {
+            index += 1;
+            if index <= self.snapshot_index {
+                continue;
+            }
+            match self.term_at(index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => self.truncate(index),
+                None => (),
+            }
+            self.push(entry);
+        }
+
+        if request.leader_commit > self.commit_index {
+            self.commit(cmp::min(request.leader_commit, index));
+        }
+        self.append_response(true)
+    }
+
+    fn last_index(&self) -> u64 {
+        self.snapshot_index + self.log.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log
+            .last()
+            .map_or(self.snapshot_term, |entry| entry.term)
+    }
+
+    /// Gets the term of the entry at `index`, if it is in the log.
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot_index {
+            Some(self.snapshot_term)
+        } else if index < self.snapshot_index {
+            None
+        } else {
+            self.log
+                .get((index - self.snapshot_index - 1) as usize)
+                .map(|entry| entry.term)
+        }
+    }
+
+    /// Adds an entry to the end of the log.
+    fn push(&mut self, entry: LogEntry) {
+        let logged = RaftLogEntry {
+            index: self.last_index() + 1,
+            entry: Some(entry.clone()),
+        };
+        match self.unwritten.last_mut() {
+            Some(RaftWrite::Append(entries)) => entries.push(logged),
+            _ => self.unwritten.push(RaftWrite::Append(vec![logged])),
+        }
+        self.log.push(entry);
+    }
+
+    /// Removes the entry at `index` and all following entries.
+    fn truncate(&mut self, index: u64) {
+        let len = (index - self.snapshot_index - 1) as usize;
+        self.log.truncate(len);
+        let logged = self.logged_entries();
+        self.unwritten.push(RaftWrite::Replace(None, logged));
+    }
+
+    /// Gets the entries after the snapshot, as they are written to disk.
+    fn logged_entries(&self) -> Vec<RaftLogEntry> {
+        self.log
+            .iter()
+            .zip(self.snapshot_index + 1..)
+            .map(|(entry, index)| RaftLogEntry {
+                index,
+                entry: Some(entry.clone()),
+            })
+            .collect()
+    }
+
+    fn save_vote(&mut self) {
+        self.unwritten.push(RaftWrite::State(RaftState {
+            term: self.term,
+            voted_for: self.voted_for.clone().unwrap_or_default(),
+        }));
+    }
+
+    fn save_snapshot(&mut self) {
+        let snapshot = RaftSnapshot {
+            index: self.snapshot_index,
+            term: self.snapshot_term,
+            shards: self.snapshot.clone(),
+        };
+        let logged = self.logged_entries();
+        self.unwritten
+            .push(RaftWrite::Replace(Some(snapshot), logged));
+    }
+
+    /// Passes any changes to the store. They are written after every earlier change, and the
+    /// returned future completes once they're on disk.
+    fn flush(&mut self) -> impl Future<Output = io::Result<()>> {
+        self.store
+            .write(mem::replace(&mut self.unwritten, Vec::new()))
+    }
+
+    fn step_down(&mut self, term: u64) {
+        if self.role == Role::Leader {
+            log::warn!("Stepping down as leader in term {}", term);
+        }
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.leader = None;
+            self.save_vote();
+        }
+        self.role = Role::Follower;
+        self.election_deadline = election_deadline();
+        for (_, committed_tx) in self.pending.drain(..) {
+            let _ = committed_tx.send(Err(Status::new(Code::Unavailable, "no longer the leader")));
+        }
+    }
+
+    fn append_request(&self, id: &str, peer: usize) -> AppendRequest {
+        let next_index = self.next_index[peer];
+        if next_index <= self.snapshot_index {
+            AppendRequest {
+                term: self.term,
+                leader_id: id.to_owned(),
+                prev_log_index: self.snapshot_index,
+                prev_log_term: self.snapshot_term,
+                entries: Vec::new(),
+                leader_commit: self.commit_index,
+                snapshot: Some(Snapshot {
+                    shards: self.snapshot.clone(),
+                }),
+            }
+        } else {
+            let prev_log_index = next_index - 1;
+            AppendRequest {
+                term: self.term,
+                leader_id: id.to_owned(),
+                prev_log_index,
+                prev_log_term: self.term_at(prev_log_index).unwrap(),
+                entries: self.log[(prev_log_index - self.snapshot_index) as usize..].to_vec(),
+                leader_commit: self.commit_index,
+                snapshot: None,
+            }
+        }
+    }
+
+    fn append_response(&self, success: bool) -> AppendResponse {
+        AppendResponse {
+            term: self.term,
+            success,
+            last_log_index: self.last_index(),
+        }
+    }
+
+    /// Commits the latest entry stored on a majority of servers, if it is from this term.
+    fn advance_commit(&mut self) {
+        let mut match_index = self.match_index.clone();
+        match_index.push(self.persisted_index);
+        match_index.sort_unstable();
+        let majority_index = match_index[(match_index.len() - 1) / 2];
+
+        if majority_index > self.commit_index && self.term_at(majority_index) == Some(self.term) {
+            self.commit(majority_index);
+        }
+    }
+
+    /// Applies all entries up to `index` and notifies any proposals waiting on them.
+    fn commit(&mut self, index: u64) {
+        if index <= self.commit_index {
+            return;
+        }
+
+        for i in self.commit_index + 1..=index {
+            let entry = &self.log[(i - self.snapshot_index - 1) as usize];
+            if let Some(entry) = entry.entry.clone() {
+                store::apply(&mut self.shards, entry);
+            }
+        }
+        self.commit_index = index;
+
+        let (committed, pending): (Vec<_>, Vec<_>) =
+            self.pending.drain(..).partition(|&(i, _)| i <= index);
+        self.pending = pending;
+        for (_, committed_tx) in committed {
+            let _ = committed_tx.send(Ok(()));
+        }
+
+        if self.log.len() > COMPACT_THRESHOLD {
+            self.compact();
+        }
+    }
+
+    /// Discards all committed entries, replacing them with a snapshot.
+    fn compact(&mut self) {
+        let len = (self.commit_index - self.snapshot_index) as usize;
+        self.snapshot_term = self.term_at(self.commit_index).unwrap();
+        self.log.drain(..len);
+        self.snapshot_index = self.commit_index;
+        self.snapshot = self.shards.clone();
+        self.save_snapshot();
+    }
+
+    fn install_snapshot(&mut self, index: u64, term: u64, shards: HashMap<String, ShardData>) {
+        log::info!("Installing snapshot at index {}", index);
+        self.log.clear();
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+        self.snapshot = shards.clone();
+        self.shards = shards;
+        self.commit_index = index;
+        self.save_snapshot();
+    }
+}
+
+fn write_failed(err: io::Error) -> Status {
+    log::error!("Failed to write cluster state: {}", err);
+    Status::new(Code::Internal, "failed to write cluster state")
+}
+
+fn election_deadline() -> Instant {
+    let (min, max) = ELECTION_TIMEOUT_MILLIS;
+    Instant::now() + Duration::from_millis(rand::thread_rng().gen_range(min, max))
+}
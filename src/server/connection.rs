@@ -1,66 +1,662 @@
-use std::collections::HashMap;
+use std::cmp::{self, Reverse};
+use std::collections::{BTreeSet, HashMap, VecDeque};
 use std::mem::replace;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use chashmap::CHashMap;
 use futures::channel::oneshot;
 use futures::Future;
+use structopt::StructOpt;
+use tokio::timer;
+use tonic::{Code, Status};
 
-use shardik::api::ShardData;
+use crate::deadlock::WaitGraph;
+use crate::stats::Stats;
+
+use shardik::api::{
+    self, KeyLock, Mode, Participant, RoutesResponse, ShardData, ShardInfo, ShardQueue, Wait,
+};
 use shardik::resource::Resource;
 
+/// How many changes to each shard's data to remember, so that clients with a recent version
+/// of the data can be sent only what has changed since.
+const HISTORY_LEN: usize = 16;
+
+#[derive(StructOpt, Clone, Copy)]
+pub struct QueueOpts {
+    /// How long a client must wait for a shard, in milliseconds, to be treated as one level
+    /// of priority more important. Zero means waiters never gain priority.
+    #[structopt(long, default_value = "1000")]
+    priority_aging: u64,
+    /// How long a client may keep a shard, in milliseconds, before it is asked to release
+    /// it for someone else. Requests which arrive in the meantime are batched, so that the
+    /// shard doesn't bounce between clients on every lock call.
+    #[structopt(long, default_value = "0")]
+    min_residency: u64,
+    /// How long a client must wait for a shard, in milliseconds, before checking whether it
+    /// is deadlocked with other clients. Zero disables deadlock detection.
+    #[structopt(long, default_value = "1000")]
+    deadlock_timeout: u64,
+    /// Start the simulated latency of a grant only once the shard has been released by its
+    /// previous holders, rather than when they were asked to release it. This is for
+    /// comparison with pipelined handoffs, which are the default.
+    #[structopt(long)]
+    serial_handoffs: bool,
+}
+
+impl QueueOpts {
+    fn aging(&self) -> Duration {
+        Duration::from_millis(self.priority_aging)
+    }
+
+    fn min_residency(&self) -> Duration {
+        Duration::from_millis(self.min_residency)
+    }
+
+    pub fn serial_handoffs(&self) -> bool {
+        self.serial_handoffs
+    }
+
+    fn deadlock_timeout(&self) -> Option<Duration> {
+        if self.deadlock_timeout == 0 {
+            None
+        } else {
+            Some(Duration::from_millis(self.deadlock_timeout))
+        }
+    }
+}
+
 pub struct ConnectionMap {
-    map: CHashMap<String, ConnectionSender>,
+    map: CHashMap<String, Shard>,
+    /// The ids of the shards in `map`, which can't be iterated over. Never locked at the
+    /// same time as a shard.
+    shard_ids: Mutex<BTreeSet<String>>,
+    /// The cluster term this map was built for, or 0 if the server is standalone.
+    generation: u64,
+    opts: QueueOpts,
+    stats: Arc<Stats>,
+    /// Always locked after any shard in `map`.
+    graph: Mutex<WaitGraph>,
+    next_holder_id: AtomicU64,
+    /// Changed whenever keys move between shards, to a version taken like one for shard
+    /// data. It starts from the latest version of the data the map was built from, deleted
+    /// shards included, which was recorded with the last change to the routes.
+    routes_version: AtomicU64,
+    /// The last fencing token handed out. It starts from the largest token in the shard
+    /// data, which is recorded for every exclusive grant, or the time the map was built if
+    /// that is later, so it keeps increasing when the map is rebuilt even without a store.
+    fencing_token: AtomicU64,
+    /// The last version given to shard data. It starts from the latest stored version, or
+    /// the time the map was built if that is later, so that a version is never reused for
+    /// different data when the map is rebuilt.
+    data_version: AtomicU64,
 }
 
 pub struct ConnectionReceiver {
     pub request_rx: oneshot::Receiver<String>,
-    pub response_tx: oneshot::Sender<ShardData>,
+    pub response_tx: ResponseSender,
+}
+
+/// Gives up a shard once it has been released.
+///
+/// If this is dropped without the shard being released (for example because the client
+/// disconnected or its lease expired), the last data known to the server is handed on
+/// instead and marked as unclean, so the shard is never lost.
+pub struct ResponseSender {
+    map: Arc<ConnectionMap>,
+    shard_id: String,
+    holder_id: u64,
+    /// Whether the client has been granted the shard, rather than still waiting for it.
+    granted: bool,
+    ended: bool,
+}
+
+/// How busy a shard has been since its load was last taken.
+#[derive(Default, Clone, Copy)]
+pub struct Load {
+    /// The number of times the shard was granted.
+    pub grants: u64,
+    /// The number of times the shard was granted while nobody held it, so it had to be
+    /// handed over from its previous holders.
+    pub handoffs: u64,
+    /// The total time clients waited for the shard before being granted it.
+    pub wait: Duration,
+}
+
+/// The shard data passed from one client to the next.
+pub struct Handoff {
+    pub data: ShardData,
+    /// Whether the previous holder failed to release the shard.
+    pub unclean: bool,
+    /// When the previous holders were asked to release the shard, if it was handed over
+    /// from holders which had to be asked.
+    pub asked_at: Option<Instant>,
+}
+
+struct Shard {
+    /// The latest data for the shard. While the shard is held exclusively this is the data
+    /// it was granted with.
+    data: ShardData,
+    unclean: bool,
+    /// The clients currently holding the shard, which are either any number of shared
+    /// holders or a single exclusive one.
+    holders: HashMap<u64, Holder>,
+    exclusive: bool,
+    waiters: VecDeque<Waiter>,
+    load: Load,
+    /// The changes made to the data by its most recent versions, oldest first.
+    history: VecDeque<ShardData>,
+    /// When the current holders were first asked to release the shard, if they have been.
+    asked_at: Option<Instant>,
+}
+
+struct Holder {
+    client_id: String,
+    mode: Mode,
+    priority: u32,
+    since: Instant,
+    /// Asks the client to release the shard. This is `None` once the request has been sent.
+    request_tx: Option<oneshot::Sender<String>>,
+    /// Whether the release request is being held back until the end of the holder's
+    /// minimum residency window.
+    deferred: bool,
 }
 
-struct ConnectionSender {
+struct Waiter {
+    holder_id: u64,
+    client_id: String,
+    mode: Mode,
+    /// Higher priority waiters are granted the shard first.
+    priority: u32,
+    since: Instant,
     request_tx: oneshot::Sender<String>,
-    response_rx: oneshot::Receiver<ShardData>,
+    grant_tx: oneshot::Sender<Handoff>,
+}
+
+/// How a holder gave up a shard.
+enum End {
+    Released(ShardData),
+    /// The client never received the shard, so it is unchanged.
+    Restored,
+    Dropped,
 }
 
 impl ConnectionMap {
-    pub fn new<R: Resource>(resource: &R) -> Self {
-        let mut map = HashMap::<String, ShardData>::new();
-        for (shard_id, key) in resource.keys() {
-            map.entry(shard_id).or_default().locks.insert(key, false);
+    /// Creates the map of shards from `shards`, replacing any found in `stored`. The stored
+    /// data includes any shards and keys added or removed since the server first started.
+    pub fn new(
+        shards: &HashMap<String, ShardData>,
+        stored: &HashMap<String, ShardData>,
+        generation: u64,
+        opts: QueueOpts,
+        stats: Arc<Stats>,
+    ) -> Self {
+        let mut shards = shards.clone();
+        for (shard_id, data) in stored {
+            if data.deleted {
+                shards.remove(shard_id);
+            } else {
+                let mut data = data.clone();
+                data.upgrade();
+                shards.insert(shard_id.clone(), data);
+            }
         }
 
-        let map = map
+        let shard_ids = shards.keys().cloned().collect();
+        let fencing_token = cmp::max(
+            shards
+                .values()
+                .map(|data| data.fencing_token)
+                .max()
+                .unwrap_or(0),
+            api::now_millis(),
+        );
+        let data_version = AtomicU64::new(cmp::max(
+            shards.values().map(|data| data.version).max().unwrap_or(0),
+            api::now_millis(),
+        ));
+        for data in shards.values_mut() {
+            // The initial shards have no version yet.
+            if data.version == 0 {
+                data.version = data_version.fetch_add(1, Ordering::SeqCst) + 1;
+            }
+        }
+        // Deleted shards count too, since deleting one changes the routes.
+        let routes_version = stored
+            .values()
+            .chain(shards.values())
+            .map(|data| data.version)
+            .max()
+            .unwrap_or(0);
+        let map = shards
             .into_iter()
-            .map(|(k, v)| (k, ConnectionSender::from_data(v)))
+            .map(|(shard_id, data)| (shard_id, Shard::new(data)))
             .collect();
+        ConnectionMap {
+            map,
+            shard_ids: Mutex::new(shard_ids),
+            generation,
+            opts,
+            stats,
+            graph: Mutex::new(WaitGraph::default()),
+            next_holder_id: AtomicU64::new(0),
+            routes_version: AtomicU64::new(routes_version),
+            fencing_token: AtomicU64::new(fencing_token),
+            data_version,
+        }
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// The ids of every shard, in order.
+    pub fn shard_ids(&self) -> Vec<String> {
+        self.shard_ids.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn contains(&self, shard_id: &str) -> bool {
+        self.map.contains_key(shard_id)
+    }
+
+    /// Finds the first of `keys` which is already in a shard other than `shard_id`, along with
+    /// the shard it is in.
+    pub fn find_key<'a>(&self, shard_id: &str, keys: &'a [String]) -> Option<(&'a str, String)> {
+        for other_id in self.shard_ids() {
+            if other_id == shard_id {
+                continue;
+            }
+            if let Some(shard) = self.map.get(&other_id) {
+                if let Some(key) = keys.iter().find(|key| shard.data.locks.contains_key(*key)) {
+                    return Some((key.as_str(), other_id));
+                }
+            }
+        }
+        None
+    }
 
-        ConnectionMap { map }
+    /// Gets the id of the shard each key belongs to.
+    pub fn routes(&self) -> RoutesResponse {
+        let version = self.routes_version.load(Ordering::SeqCst);
+        let mut shards = HashMap::new();
+        for shard_id in self.shard_ids() {
+            if let Some(shard) = self.map.get(&shard_id) {
+                for key in shard.data.locks.keys() {
+                    shards.insert(key.clone(), shard_id.clone());
+                }
+            }
+        }
+        RoutesResponse { shards, version }
+    }
+
+    pub fn routes_version(&self) -> u64 {
+        self.routes_version.load(Ordering::SeqCst)
+    }
+
+    /// Marks the routes as changed, for when keys have been added to or removed from a
+    /// shard.
+    pub fn change_routes(&self) {
+        let version = self.next_version();
+        let mut current = self.routes_version.load(Ordering::SeqCst);
+        // Another change may have taken a later version and stored it first.
+        while current < version {
+            match self.routes_version.compare_exchange(
+                current,
+                version,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => break,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// Takes a new fencing token, larger than any handed out before.
+    fn next_fencing_token(&self) -> u64 {
+        self.fencing_token.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Takes a new version for shard data which has changed.
+    pub fn next_version(&self) -> u64 {
+        self.data_version.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Gets the changes to a shard since the given version of its data, or `None` if the
+    /// shard's history doesn't go back that far.
+    pub fn delta(&self, shard_id: &str, since: u64) -> Option<ShardData> {
+        self.map.get(shard_id)?.delta(since)
+    }
+
+    /// Turns data released by the exclusive holder of a shard back into all of the data,
+    /// if it was sent as a delta from the data the holder was granted.
+    pub fn resolve(&self, shard_id: &str, data: ShardData) -> Result<ShardData, Status> {
+        if !data.is_delta() {
+            return Ok(data);
+        }
+        let shard = self
+            .map
+            .get(shard_id)
+            .ok_or_else(|| Status::new(Code::NotFound, format!("shard {} not found", shard_id)))?;
+        data.resolve(Some(&shard.data))
+    }
+
+    /// Gets how busy each shard has been since this was last called, and starts counting
+    /// again.
+    pub fn take_load(&self) -> Vec<(String, Load)> {
+        self.shard_ids()
+            .into_iter()
+            .filter_map(|shard_id| {
+                let mut shard = self.map.get_mut(&shard_id)?;
+                let load = replace(&mut shard.load, Load::default());
+                Some((shard_id, load))
+            })
+            .collect()
+    }
+
+    /// Describes the keys in a shard. Clients can't add or remove keys, so the server's copy
+    /// of the key set is up to date even while a client holds the shard.
+    pub fn info(&self, shard_id: &str) -> Option<ShardInfo> {
+        let shard = self.map.get(shard_id)?;
+        let mut keys: Vec<String> = shard.data.locks.keys().cloned().collect();
+        keys.sort();
+        Some(ShardInfo {
+            shard_id: shard_id.to_owned(),
+            keys,
+        })
+    }
+
+    /// Adds a new shard, returning false if there is already one with the same id.
+    pub fn create(&self, shard_id: &str, data: ShardData) -> bool {
+        let mut created = false;
+        self.map.upsert(
+            shard_id.to_owned(),
+            || {
+                created = true;
+                Shard::new(data)
+            },
+            |_| (),
+        );
+        if created {
+            self.shard_ids.lock().unwrap().insert(shard_id.to_owned());
+            self.change_routes();
+        }
+        created
+    }
+
+    /// Removes a shard. Anyone still waiting for it fails with `NotFound`.
+    pub fn remove(&self, shard_id: &str) -> bool {
+        if self.map.remove(shard_id).is_none() {
+            return false;
+        }
+        self.shard_ids.lock().unwrap().remove(shard_id);
+        self.change_routes();
+        true
     }
 
     /// Gets a shard with the given id, returning the shard data and a `ConnectionReceiver` to
-    /// listen to to know when to release the shard.
-    pub async fn begin(&self, id: &str) -> Option<(ConnectionReceiver, ShardData)> {
+    /// listen to to know when to release the shard, and to give it up once released.
+    ///
+    /// Any number of clients may hold a shard in shared mode, but a client holding it in
+    /// exclusive mode has sole access. Waiters are granted the shard in order of priority,
+    /// then in the order they arrive, and the current holders are asked to release it
+    /// whenever anyone is waiting.
+    ///
+    /// With `Wait::Never`, fails with `ResourceExhausted` unless the shard can be granted
+    /// straight away, without joining the queue or asking anyone to release it. Otherwise if
+    /// the shard can't be granted within the time allowed by `wait`, the waiter is taken
+    /// out of the queue again and an error is returned. The same happens if the returned
+    /// future is dropped, the waiter is cancelled with `cancel`, or the client is found to be
+    /// deadlocked with other clients after waiting for the deadlock timeout. Deadlocks are
+    /// found between owners, which are ids given out by the server to tell clients apart,
+    /// and only the waiter with the highest owner in each cycle is aborted.
+    pub async fn begin(
+        self: Arc<Self>,
+        id: &str,
+        client_id: &str,
+        owner: u64,
+        mode: Mode,
+        priority: u32,
+        wait: Wait,
+    ) -> Result<(ConnectionReceiver, Handoff), Status> {
+        let holder_id = self.next_holder_id.fetch_add(1, Ordering::Relaxed);
         let (request_tx, request_rx) = oneshot::channel();
-        let (response_tx, response_rx) = oneshot::channel();
-        let cur_sender = ConnectionSender {
-            request_tx,
-            response_rx,
+        let (grant_tx, mut grant_rx) = oneshot::channel();
+
+        match self.map.get_mut(id) {
+            Some(mut shard) => {
+                if wait == Wait::Never && !shard.is_available(mode) {
+                    return Err(Status::new(
+                        Code::ResourceExhausted,
+                        format!("shard {} is held by another client", id),
+                    ));
+                }
+                if shard.is_deferred() && !shard.waiters.is_empty() {
+                    self.stats.avoid_handoff();
+                }
+                shard.waiters.push_back(Waiter {
+                    holder_id,
+                    client_id: client_id.to_owned(),
+                    mode,
+                    priority,
+                    since: Instant::now(),
+                    request_tx,
+                    grant_tx,
+                });
+                self.graph.lock().unwrap().wait(holder_id, owner, id);
+                self.grant(id, &mut shard);
+            }
+            None => {
+                return Err(Status::new(
+                    Code::NotFound,
+                    format!("shard {} not found", id),
+                ))
+            }
+        }
+
+        // Created before waiting so that the waiter is removed again if we give up, or this
+        // future is dropped.
+        let mut response_tx = ResponseSender {
+            map: self.clone(),
+            shard_id: id.to_owned(),
+            holder_id,
+            granted: false,
+            ended: false,
         };
+        let timeout_at = match wait {
+            Wait::For(timeout) => Some(Instant::now() + timeout),
+            _ => None,
+        };
+        let grant = loop {
+            let check_at = self
+                .opts
+                .deadlock_timeout()
+                .map(|timeout| Instant::now() + timeout);
+            let wake_at = match (timeout_at, check_at) {
+                (Some(timeout_at), Some(check_at)) => cmp::min(timeout_at, check_at),
+                (Some(at), None) | (None, Some(at)) => at,
+                (None, None) => break (&mut grant_rx).await,
+            };
+            if let Ok(grant) = timer::Timeout::new_at(&mut grant_rx, wake_at).await {
+                break grant;
+            }
+
+            if timeout_at.map_or(false, |timeout_at| Instant::now() >= timeout_at) {
+                return Err(Status::new(
+                    Code::DeadlineExceeded,
+                    format!("timed out waiting for shard {}", id),
+                ));
+            }
+            if self.graph.lock().unwrap().is_victim(holder_id) {
+                log::warn!(
+                    "Aborting request from {} for shard {} to break a deadlock",
+                    client_id,
+                    id
+                );
+                return Err(Status::new(
+                    Code::Aborted,
+                    format!("deadlock detected waiting for shard {}", id),
+                ));
+            }
+        };
+        let handoff = grant.map_err(|_| {
+            if self.map.contains_key(id) {
+                Status::new(
+                    Code::Cancelled,
+                    format!("waiting for shard {} was cancelled", id),
+                )
+            } else {
+                Status::new(Code::NotFound, format!("shard {} was removed", id))
+            }
+        })?;
+        response_tx.granted = true;
+
         let cur_receiver = ConnectionReceiver {
             request_rx,
             response_tx,
         };
+        Ok((cur_receiver, handoff))
+    }
+
+    /// Describes who holds a shard and who is waiting for it.
+    pub fn queue(&self, shard_id: &str) -> Option<ShardQueue> {
+        let shard = self.map.get(shard_id)?;
+        let now = Instant::now();
+        let aging = self.opts.aging();
+        let participant =
+            |client_id: &str, mode: Mode, priority: u32, since: Instant| Participant {
+                client_id: client_id.to_owned(),
+                mode: mode as i32,
+                priority,
+                elapsed_millis: (now - since).as_millis() as u64,
+            };
+
+        let mut holders: Vec<_> = shard.holders.values().collect();
+        holders.sort_by_key(|holder| holder.since);
+        let mut waiters: Vec<_> = shard.waiters.iter().enumerate().collect();
+        waiters.sort_by_key(|&(index, waiter)| Reverse(waiter.order(index, aging, now)));
+        Some(ShardQueue {
+            holders: holders
+                .into_iter()
+                .map(|holder| {
+                    participant(
+                        &holder.client_id,
+                        holder.mode,
+                        holder.priority,
+                        holder.since,
+                    )
+                })
+                .collect(),
+            waiters: waiters
+                .into_iter()
+                .map(|(_, waiter)| {
+                    participant(
+                        &waiter.client_id,
+                        waiter.mode,
+                        waiter.priority,
+                        waiter.since,
+                    )
+                })
+                .collect(),
+        })
+    }
+
+    /// Removes every waiter from `client_id` from the queue for a shard, failing their
+    /// requests. Returns the number of waiters removed.
+    pub fn cancel(self: &Arc<Self>, shard_id: &str, client_id: &str) -> Option<usize> {
+        let mut shard = self.map.get_mut(shard_id)?;
+        let len = shard.waiters.len();
+        shard.waiters.retain(|waiter| waiter.client_id != client_id);
+        let cancelled = len - shard.waiters.len();
+        if cancelled != 0 {
+            // Someone else may now be at the front of the queue.
+            self.grant(shard_id, &mut shard);
+        }
+        Some(cancelled)
+    }
+
+    /// Removes a holder or waiter from a shard, and grants it to any waiters who can now
+    /// have it.
+    fn end(self: &Arc<Self>, shard_id: &str, holder_id: u64, end: End) {
+        let mut shard = match self.map.get_mut(shard_id) {
+            Some(shard) => shard,
+            None => {
+                // The shard has been removed.
+                self.graph.lock().unwrap().remove(holder_id);
+                return;
+            }
+        };
+
+        if shard.holders.remove(&holder_id).is_some() {
+            if shard.exclusive {
+                match end {
+                    End::Released(data) => {
+                        // The token is the server's to set, whatever the client sent back.
+                        let fencing_token = shard.data.fencing_token;
+                        let data = ShardData {
+                            fencing_token,
+                            ..data
+                        };
+                        if data.version != shard.data.version {
+                            let delta = data.delta_from(&shard.data);
+                            shard.record_change(delta);
+                        }
+                        shard.data = data;
+                        shard.unclean = false;
+                    }
+                    End::Restored => (),
+                    End::Dropped => {
+                        log::warn!(
+                            "Shard {} was not released, handing on last known data",
+                            shard_id
+                        );
+                        shard.unclean = true;
+                    }
+                }
+            }
+        } else {
+            shard.waiters.retain(|waiter| waiter.holder_id != holder_id);
+        }
+        self.graph.lock().unwrap().remove(holder_id);
+
+        self.grant(shard_id, &mut shard);
+    }
 
-        let prev_sender = {
-            let mut shard = self.map.get_mut(id)?;
-            replace(&mut *shard, cur_sender)
+    /// Grants a shard to whoever can have it. If the holders need to be asked to release it
+    /// but are still within their minimum residency window, tries again once the window is
+    /// over.
+    fn grant(self: &Arc<Self>, shard_id: &str, shard: &mut Shard) {
+        let deadline = match shard.grant(shard_id, self) {
+            Some(deadline) => deadline,
+            None => return,
         };
-        let data = prev_sender.acquire(id.to_owned()).await;
 
-        Some((cur_receiver, data))
+        let map = self.clone();
+        let shard_id = shard_id.to_owned();
+        tokio::spawn(async move {
+            timer::delay(deadline).await;
+            if let Some(mut shard) = map.map.get_mut(&shard_id) {
+                map.grant(&shard_id, &mut shard);
+            }
+        });
     }
 }
 
+/// Builds the initial data for each shard from the keys of `resource`.
+pub fn initial_shards<R: Resource>(resource: &R) -> HashMap<String, ShardData> {
+    let mut shards = HashMap::<String, ShardData>::new();
+    for (shard_id, key) in resource.keys() {
+        shards
+            .entry(shard_id)
+            .or_default()
+            .locks
+            .insert(key, KeyLock::default());
+    }
+    shards
+}
+
 impl ConnectionReceiver {
     pub async fn request_release<F, T>(request_rx: oneshot::Receiver<String>, release: F)
     where
@@ -73,22 +669,331 @@ impl ConnectionReceiver {
     }
 }
 
-impl ConnectionSender {
-    fn from_data(data: ShardData) -> Self {
-        let (request_tx, _) = oneshot::channel();
-        let (response_tx, response_rx) = oneshot::channel();
+impl ResponseSender {
+    /// Gives up the shard with the data released by the client. The data is ignored if the
+    /// shard was held in shared mode.
+    pub fn send(mut self, data: ShardData) {
+        self.end(End::Released(data));
+    }
+
+    /// Gives up the shard unchanged, for when the client never received it.
+    pub fn restore(mut self) {
+        self.end(End::Restored);
+    }
+
+    fn end(&mut self, end: End) {
+        if !self.ended {
+            self.ended = true;
+            self.map.end(&self.shard_id, self.holder_id, end);
+        }
+    }
+}
+
+impl Drop for ResponseSender {
+    fn drop(&mut self) {
+        if self.granted {
+            self.end(End::Dropped);
+        } else {
+            // The shard may have been granted after we stopped waiting, but the client never
+            // saw it.
+            self.end(End::Restored);
+        }
+    }
+}
+
+impl Shard {
+    fn new(data: ShardData) -> Self {
+        Shard {
+            data,
+            unclean: false,
+            holders: HashMap::new(),
+            exclusive: false,
+            waiters: VecDeque::new(),
+            load: Load::default(),
+            history: VecDeque::new(),
+            asked_at: None,
+        }
+    }
+
+    /// Remembers a change to the data, for sending to clients which have the version before.
+    fn record_change(&mut self, delta: ShardData) {
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(delta);
+    }
+
+    /// Gets the changes since the given version of the data, or `None` if the history
+    /// doesn't go back that far.
+    fn delta(&self, since: u64) -> Option<ShardData> {
+        if since == 0 {
+            return None;
+        }
+        let start = if since == self.data.version {
+            self.history.len()
+        } else {
+            self.history
+                .iter()
+                .position(|delta| delta.base_version == since)?
+        };
+        let mut delta = ShardData {
+            base_version: since,
+            version: since,
+            ..ShardData::default()
+        };
+        for next in self.history.iter().skip(start) {
+            delta.merge_delta(next.clone());
+        }
+        if delta.version != self.data.version {
+            return None;
+        }
+        // The fencing token changes with every exclusive grant, without the data changing.
+        delta.fencing_token = self.data.fencing_token;
+        delta.deleted = self.data.deleted;
+        Some(delta)
+    }
+
+    /// Grants the shard to the most important waiters for as long as they are compatible
+    /// with the current holders, then asks the holders to release it if anyone is still
+    /// waiting. Returns when to try again if any holder's release was held back.
+    fn grant(&mut self, shard_id: &str, map: &ConnectionMap) -> Option<Instant> {
+        let now = Instant::now();
+        let aging = map.opts.aging();
+        // Only the waiters granted the shard once its holders have all released it were
+        // waiting for them to.
+        let asked_at = if self.holders.is_empty() {
+            self.asked_at.take()
+        } else {
+            None
+        };
+        while let Some(index) = self.next_waiter(aging, now) {
+            let waiter = &self.waiters[index];
+            let compatible =
+                self.holders.is_empty() || (!self.exclusive && waiter.mode == Mode::Shared);
+            if !compatible {
+                break;
+            }
+
+            let waiter = self.waiters.remove(index).unwrap();
+            let handed_over = self.holders.is_empty();
+            if handed_over {
+                self.expire_locks(shard_id, map);
+            }
+            if waiter.mode == Mode::Exclusive {
+                self.data.fencing_token = map.next_fencing_token();
+            }
+            let handoff = Handoff {
+                data: self.data.clone(),
+                unclean: self.unclean,
+                asked_at,
+            };
+            if waiter.grant_tx.send(handoff).is_err() {
+                // The waiter has gone away.
+                continue;
+            }
+
+            map.stats.grant();
+            self.load.grants += 1;
+            if handed_over {
+                self.load.handoffs += 1;
+            }
+            self.load.wait += now - waiter.since;
+            map.graph.lock().unwrap().hold(waiter.holder_id);
+            self.exclusive = waiter.mode == Mode::Exclusive;
+            if self.exclusive {
+                self.unclean = false;
+            }
+            self.holders.insert(
+                waiter.holder_id,
+                Holder {
+                    client_id: waiter.client_id,
+                    mode: waiter.mode,
+                    priority: waiter.priority,
+                    since: now,
+                    request_tx: Some(waiter.request_tx),
+                    deferred: false,
+                },
+            );
+        }
+
+        if self.waiters.is_empty() {
+            return None;
+        }
+        let mut retry_at = None;
+        for holder in self.holders.values_mut() {
+            if holder.request_tx.is_none() {
+                continue;
+            }
+
+            let release_at = holder.since + map.opts.min_residency();
+            if now < release_at {
+                if !holder.deferred {
+                    holder.deferred = true;
+                    map.stats.defer_release();
+                    retry_at = Some(retry_at.map_or(release_at, |at| cmp::min(at, release_at)));
+                }
+                continue;
+            }
+
+            let request_tx = holder.request_tx.take().unwrap();
+            self.asked_at.get_or_insert(now);
+            let _ = request_tx.send(shard_id.to_owned());
+        }
+        retry_at
+    }
+
+    /// Clears the locks on any keys whose owners didn't unlock them in time.
+    fn expire_locks(&mut self, shard_id: &str, map: &ConnectionMap) {
+        let expired = self.data.expire_locks();
+        if !expired.is_empty() {
+            let version = map.next_version();
+            let delta = ShardData {
+                locks: expired
+                    .iter()
+                    .map(|(key, _)| (key.clone(), KeyLock::default()))
+                    .collect(),
+                version,
+                base_version: self.data.version,
+                ..ShardData::default()
+            };
+            self.data.version = version;
+            self.record_change(delta);
+        }
+        for (key, lock) in expired {
+            log::warn!(
+                "Forcibly unlocked key {} in shard {}, its lock by {} expired",
+                key,
+                shard_id,
+                lock.owner
+            );
+            map.stats.expire_key();
+        }
+    }
+
+    /// Whether a new waiter in `mode` would be granted the shard straight away, without
+    /// anyone being asked to release it.
+    fn is_available(&self, mode: Mode) -> bool {
+        self.waiters.is_empty()
+            && (self.holders.is_empty() || (!self.exclusive && mode == Mode::Shared))
+    }
+
+    /// Whether a release request is being held back for any holder.
+    fn is_deferred(&self) -> bool {
+        self.holders
+            .values()
+            .any(|holder| holder.deferred && holder.request_tx.is_some())
+    }
+
+    /// Gets the index of the waiter to grant the shard to next.
+    fn next_waiter(&self, aging: Duration, now: Instant) -> Option<usize> {
+        self.waiters
+            .iter()
+            .enumerate()
+            .max_by_key(|&(index, waiter)| waiter.order(index, aging, now))
+            .map(|(index, _)| index)
+    }
+}
+
+impl Waiter {
+    /// The key waiters are granted the shard in, from highest to lowest. Each `aging` spent
+    /// waiting adds one to a waiter's priority so that low priority waiters aren't starved,
+    /// and ties go to whoever is nearer the front of the queue.
+    fn order(&self, index: usize, aging: Duration, now: Instant) -> (u64, Reverse<usize>) {
+        let age = if aging == Duration::from_secs(0) {
+            0
+        } else {
+            ((now - self.since).as_nanos() / aging.as_nanos()) as u64
+        };
+        (u64::from(self.priority) + age, Reverse(index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        response_tx.send(data).unwrap();
+    fn data(version: u64, owners: &[(&str, &str)]) -> ShardData {
+        ShardData {
+            locks: owners
+                .iter()
+                .map(|&(key, owner)| {
+                    let lock = KeyLock {
+                        owner: owner.to_owned(),
+                        count: if owner.is_empty() { 0 } else { 1 },
+                        ..KeyLock::default()
+                    };
+                    (key.to_owned(), lock)
+                })
+                .collect(),
+            version,
+            ..ShardData::default()
+        }
+    }
 
-        ConnectionSender {
-            request_tx,
-            response_rx,
+    /// Changes the shard's data the way releasing it exclusively does.
+    fn change(shard: &mut Shard, next: ShardData) {
+        let delta = next.delta_from(&shard.data);
+        shard.record_change(delta);
+        shard.data = next;
+    }
+
+    /// Makes a shard which has been through the versions 1, 2 and 3.
+    fn shard() -> (Shard, Vec<ShardData>) {
+        let versions = vec![
+            data(1, &[("a", ""), ("b", "")]),
+            data(2, &[("a", "x"), ("b", "")]),
+            data(3, &[("a", "x"), ("b", "y")]),
+        ];
+        let mut shard = Shard::new(versions[0].clone());
+        for next in &versions[1..] {
+            change(&mut shard, next.clone());
         }
+        (shard, versions)
     }
 
-    /// Request the shard from another client, and wait for it to be returned.
-    pub async fn acquire(self, id: String) -> ShardData {
-        let _ = self.request_tx.send(id);
-        self.response_rx.await.unwrap()
+    #[test]
+    fn delta_from_each_earlier_version_gives_the_latest_data() {
+        let (shard, versions) = shard();
+        for base in &versions {
+            let delta = shard.delta(base.version).unwrap();
+            assert_eq!(delta.base_version, base.version);
+            assert_eq!(delta.resolve(Some(base)).unwrap(), versions[2]);
+        }
+    }
+
+    #[test]
+    fn delta_from_the_latest_version_is_empty() {
+        let (shard, _) = shard();
+        let delta = shard.delta(3).unwrap();
+        assert!(delta.locks.is_empty());
+        assert!(delta.removed_keys.is_empty());
+        assert_eq!(delta.version, 3);
+    }
+
+    #[test]
+    fn no_delta_from_unknown_versions() {
+        let (shard, _) = shard();
+        assert!(shard.delta(0).is_none());
+        assert!(shard.delta(4).is_none());
+    }
+
+    #[test]
+    fn delta_has_the_latest_fencing_token() {
+        let (mut shard, versions) = shard();
+        shard.data.fencing_token = 9;
+        let delta = shard.delta(1).unwrap();
+        assert_eq!(delta.fencing_token, 9);
+        assert_eq!(delta.resolve(Some(&versions[0])).unwrap().fencing_token, 9);
+    }
+
+    #[test]
+    fn history_is_limited() {
+        let mut shard = Shard::new(data(1, &[("a", "")]));
+        for version in 2..=HISTORY_LEN as u64 + 2 {
+            let owner = if version % 2 == 0 { "x" } else { "" };
+            change(&mut shard, data(version, &[("a", owner)]));
+        }
+        assert!(shard.delta(1).is_none());
+        assert!(shard.delta(2).is_some());
     }
 }
@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+
+/// Tracks which clients hold and wait for which shards, to find clients which are waiting
+/// on each other in a cycle.
+///
+/// Clients are identified by an owner id the server gives each session, and each `lock`
+/// stream or admin request outside of a session, rather than the id they give themselves,
+/// which needn't be unique.
+#[derive(Default)]
+pub struct WaitGraph {
+    /// Every holder and waiter, by the id they were given in the `ConnectionMap`.
+    entries: HashMap<u64, Entry>,
+}
+
+struct Entry {
+    owner: u64,
+    shard_id: String,
+    waiting: bool,
+}
+
+impl WaitGraph {
+    /// Records that a client has started waiting for a shard.
+    pub fn wait(&mut self, id: u64, owner: u64, shard_id: &str) {
+        self.entries.insert(
+            id,
+            Entry {
+                owner,
+                shard_id: shard_id.to_owned(),
+                waiting: true,
+            },
+        );
+    }
+
+    /// Records that a waiter has been granted its shard.
+    pub fn hold(&mut self, id: u64) {
+        if let Some(entry) = self.entries.get_mut(&id) {
+            entry.waiting = false;
+        }
+    }
+
+    pub fn remove(&mut self, id: u64) {
+        self.entries.remove(&id);
+    }
+
+    /// Whether the waiter `id` should be aborted to break a deadlock. It is deadlocked if it
+    /// is waiting for a shard held by a client which is, directly or through other clients,
+    /// waiting for a shard held by the waiter's own client. Every waiter in the cycle checks
+    /// this, so only the one whose owner has the highest id is aborted, rather than all of
+    /// them if they happen to check at the same moment.
+    pub fn is_victim(&self, id: u64) -> bool {
+        let entry = match self.entries.get(&id) {
+            Some(entry) if entry.waiting => entry,
+            _ => return false,
+        };
+        self.cycle(entry).map_or(false, |owners| {
+            owners.iter().all(|&owner| owner <= entry.owner)
+        })
+    }
+
+    /// Finds the owners in a cycle of waiters which starts from `entry`, if there is one.
+    fn cycle(&self, entry: &Entry) -> Option<Vec<u64>> {
+        // The owner each owner was reached from, so that the cycle can be traced back.
+        let mut reached_from = HashMap::new();
+        let mut waits = vec![(entry.owner, entry.shard_id.as_str())];
+        while let Some((waiter, shard_id)) = waits.pop() {
+            for holder in self.holders(shard_id) {
+                if holder == entry.owner {
+                    let mut owners = vec![waiter];
+                    let mut owner = waiter;
+                    while owner != entry.owner {
+                        owner = reached_from[&owner];
+                        owners.push(owner);
+                    }
+                    return Some(owners);
+                }
+                if !reached_from.contains_key(&holder) {
+                    reached_from.insert(holder, waiter);
+                    waits.extend(self.waiting_for(holder).map(|shard_id| (holder, shard_id)));
+                }
+            }
+        }
+        None
+    }
+
+    fn holders<'a>(&'a self, shard_id: &'a str) -> impl Iterator<Item = u64> + 'a {
+        self.entries
+            .values()
+            .filter(move |entry| !entry.waiting && entry.shard_id == shard_id)
+            .map(|entry| entry.owner)
+    }
+
+    fn waiting_for<'a>(&'a self, owner: u64) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .values()
+            .filter(move |entry| entry.waiting && entry.owner == owner)
+            .map(|entry| entry.shard_id.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn held(graph: &mut WaitGraph, id: u64, owner: u64, shard_id: &str) {
+        graph.wait(id, owner, shard_id);
+        graph.hold(id);
+    }
+
+    fn is_deadlocked(graph: &WaitGraph, id: u64) -> bool {
+        let entry = &graph.entries[&id];
+        entry.waiting && graph.cycle(entry).is_some()
+    }
+
+    #[test]
+    fn waiting_for_a_holder_is_not_a_deadlock() {
+        let mut graph = WaitGraph::default();
+        held(&mut graph, 1, 10, "a");
+        graph.wait(2, 20, "a");
+        assert!(!is_deadlocked(&graph, 2));
+        assert!(!is_deadlocked(&graph, 1));
+    }
+
+    #[test]
+    fn finds_a_cycle_between_two_owners() {
+        let mut graph = WaitGraph::default();
+        held(&mut graph, 1, 10, "a");
+        held(&mut graph, 2, 20, "b");
+        graph.wait(3, 10, "b");
+        graph.wait(4, 20, "a");
+        assert!(is_deadlocked(&graph, 3));
+        assert!(is_deadlocked(&graph, 4));
+    }
+
+    #[test]
+    fn finds_a_cycle_through_several_owners() {
+        let mut graph = WaitGraph::default();
+        held(&mut graph, 1, 10, "a");
+        held(&mut graph, 2, 20, "b");
+        held(&mut graph, 3, 30, "c");
+        graph.wait(4, 10, "b");
+        graph.wait(5, 20, "c");
+        assert!(!is_deadlocked(&graph, 4));
+        graph.wait(6, 30, "a");
+        assert!(is_deadlocked(&graph, 4));
+        assert!(is_deadlocked(&graph, 5));
+        assert!(is_deadlocked(&graph, 6));
+    }
+
+    #[test]
+    fn releasing_a_shard_breaks_the_cycle() {
+        let mut graph = WaitGraph::default();
+        held(&mut graph, 1, 10, "a");
+        held(&mut graph, 2, 20, "b");
+        graph.wait(3, 10, "b");
+        graph.wait(4, 20, "a");
+        graph.remove(1);
+        assert!(!is_deadlocked(&graph, 3));
+        assert!(!is_deadlocked(&graph, 4));
+    }
+
+    #[test]
+    fn owners_are_not_confused_with_each_other() {
+        // Two owners which may well have given the same client id, each holding one shard
+        // and waiting for the other's, but not both at once.
+        let mut graph = WaitGraph::default();
+        held(&mut graph, 1, 10, "a");
+        held(&mut graph, 2, 20, "b");
+        graph.wait(3, 10, "b");
+        graph.wait(4, 30, "a");
+        assert!(!is_deadlocked(&graph, 3));
+        assert!(!is_deadlocked(&graph, 4));
+    }
+
+    #[test]
+    fn waiting_for_a_shard_the_owner_holds_is_a_deadlock() {
+        let mut graph = WaitGraph::default();
+        held(&mut graph, 1, 10, "a");
+        graph.wait(2, 10, "a");
+        assert!(is_deadlocked(&graph, 2));
+    }
+
+    #[test]
+    fn only_the_highest_owner_in_a_cycle_is_the_victim() {
+        let mut graph = WaitGraph::default();
+        held(&mut graph, 1, 10, "a");
+        held(&mut graph, 2, 30, "b");
+        held(&mut graph, 3, 20, "c");
+        graph.wait(4, 10, "b");
+        graph.wait(5, 30, "c");
+        graph.wait(6, 20, "a");
+        assert!(!graph.is_victim(4));
+        assert!(graph.is_victim(5));
+        assert!(!graph.is_victim(6));
+        assert!(!graph.is_victim(1));
+
+        // Once the victim gives up, nobody else needs to.
+        graph.remove(5);
+        assert!(!graph.is_victim(4));
+        assert!(!graph.is_victim(6));
+    }
+}
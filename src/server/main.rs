@@ -1,28 +1,67 @@
+mod cluster;
 mod connection;
+mod deadlock;
+mod rebalance;
 mod service;
+mod stats;
+mod store;
 
 use std::io::Write;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
+use futures::future;
 use structopt::StructOpt;
 use tonic::transport::Server;
 
-use crate::service::LockService;
+use crate::cluster::{Cluster, ClusterService};
+use crate::connection::QueueOpts;
+use crate::rebalance::RebalanceOpts;
+use crate::service::{LockService, Topology};
+use crate::store::{MemoryStore, Store, StoreOpts};
 use shardik::api::*;
 use shardik::resource::FileSystem;
+use shardik::ring::Ring;
 
 #[derive(StructOpt)]
 struct Opts {
     /// The endpoint to listen on.
     #[structopt(long, default_value = "[::1]:10000")]
     endpoint: SocketAddr,
+    /// The endpoint to listen on for traffic from other servers in the cluster.
+    #[structopt(long, default_value = "[::1]:11000")]
+    cluster_endpoint: SocketAddr,
+    /// The cluster endpoint of another server in the cluster. If none are given, the server
+    /// runs standalone.
+    #[structopt(long = "peer")]
+    peers: Vec<http::Uri>,
+    /// The endpoint of a server to partition shards across, including this one. Shards are
+    /// assigned to nodes using a consistent hash ring.
+    #[structopt(long = "node")]
+    nodes: Vec<String>,
     #[structopt(flatten)]
     fs: FileSystem,
-    /// The simulated latency of the service in milliseconds.
+    #[structopt(flatten)]
+    store: StoreOpts,
+    /// The simulated latency of the service in milliseconds. Each response is sent this long
+    /// after it is ready, except that a grant handed over from holders which were asked to
+    /// release the shard counts it from when they were asked, unless `--serial-handoffs` is
+    /// set.
     #[structopt(long, default_value = "40")]
     latency: u64,
+    /// How long a client may hold a shard without renewing its lease, in milliseconds.
+    #[structopt(long, default_value = "30000")]
+    lease: u64,
+    /// How long a client's session lasts without a heartbeat, in milliseconds. When it
+    /// expires, the shards held on it are handed on.
+    #[structopt(long, default_value = "10000")]
+    session_ttl: u64,
+    #[structopt(flatten)]
+    queue: QueueOpts,
+    #[structopt(flatten)]
+    rebalance: RebalanceOpts,
 }
 
 #[tokio::main]
@@ -42,11 +81,62 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     log::info!("Listening on: {}", opts.endpoint);
 
+    let id = format!("http://{}", opts.endpoint);
+    let topology = match (opts.peers.is_empty(), opts.nodes.is_empty()) {
+        (true, true) => Topology::Standalone,
+        (false, true) => {
+            let (store, saved) = store::open_raft(&opts.store)?;
+            Topology::Cluster(Arc::new(Cluster::new(id, opts.peers, store, saved)?))
+        }
+        (true, false) => {
+            if !opts.nodes.contains(&id) {
+                return Err(format!("the nodes in the ring must include {}", id).into());
+            }
+            Topology::Partitioned {
+                id,
+                ring: Ring::new(opts.nodes),
+            }
+        }
+        (false, false) => return Err("cannot use both `--peer` and `--node`".into()),
+    };
+
+    if opts.lease == 0 || opts.session_ttl == 0 {
+        return Err("`--lease` and `--session-ttl` must be greater than zero".into());
+    }
+
     let resource = opts.fs;
-    let svc = server::LockServiceServer::new(LockService::new(
+    let store: Arc<dyn Store> = match &topology {
+        // Shard data is persisted as part of the replicated log instead.
+        Topology::Cluster(_) => Arc::new(MemoryStore),
+        _ => store::open(opts.store)?,
+    };
+    let service = LockService::new(
         &resource,
+        store,
+        topology.clone(),
         Duration::from_millis(opts.latency),
-    ));
-    Server::builder().serve(opts.endpoint, svc).await?;
+        Duration::from_millis(opts.lease),
+        Duration::from_millis(opts.session_ttl),
+        opts.queue,
+    )?;
+    tokio::spawn(service.clone().rebalance(opts.rebalance));
+    let svc = server::LockServiceServer::new(service);
+
+    match topology {
+        Topology::Cluster(cluster) => {
+            log::info!(
+                "Listening for cluster traffic on: {}",
+                opts.cluster_endpoint
+            );
+            tokio::spawn(cluster.clone().run());
+            let cluster_svc = server::ClusterServiceServer::new(ClusterService::new(cluster));
+            future::try_join(
+                Server::builder().serve(opts.endpoint, svc),
+                Server::builder().serve(opts.cluster_endpoint, cluster_svc),
+            )
+            .await?;
+        }
+        _ => Server::builder().serve(opts.endpoint, svc).await?,
+    }
     Ok(())
 }
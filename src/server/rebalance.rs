@@ -0,0 +1,47 @@
+use std::cmp;
+use std::time::Duration;
+
+use structopt::StructOpt;
+
+use crate::connection::Load;
+
+#[derive(StructOpt, Clone, Copy)]
+pub struct RebalanceOpts {
+    /// How often to look for hot shards in milliseconds. Hot shards are split in two, so
+    /// that clients using different keys stop contending for them. Zero disables automatic
+    /// rebalancing.
+    #[structopt(long, default_value = "0")]
+    rebalance_interval: u64,
+    /// How many times per second a shard must be handed from one client to another to be
+    /// hot.
+    #[structopt(long, default_value = "10")]
+    hot_handoff_rate: f64,
+    /// How long clients must have waited for a shard on average, in milliseconds, for it to
+    /// be hot.
+    #[structopt(long, default_value = "100")]
+    hot_wait: u64,
+}
+
+impl RebalanceOpts {
+    pub fn interval(&self) -> Option<Duration> {
+        if self.rebalance_interval == 0 {
+            None
+        } else {
+            Some(Duration::from_millis(self.rebalance_interval))
+        }
+    }
+
+    /// Whether a shard with the given load over `elapsed` is busy enough to split. Both the
+    /// handoff rate and the mean wait must be over their thresholds, since a shard can be
+    /// handed on often without anyone waiting long for it.
+    pub fn is_hot(&self, load: &Load, elapsed: Duration) -> bool {
+        if load.grants == 0 || elapsed == Duration::from_secs(0) {
+            return false;
+        }
+        let handoff_rate = load.handoffs as f64 / elapsed.as_secs_f64();
+        // In nanoseconds, since a `Duration` can only be divided by a `u32`.
+        let mean_wait = load.wait.as_nanos() / u128::from(cmp::max(load.grants, 1));
+        handoff_rate >= self.hot_handoff_rate
+            && mean_wait >= Duration::from_millis(self.hot_wait).as_nanos()
+    }
+}
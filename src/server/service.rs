@@ -1,24 +1,55 @@
-use std::sync::Arc;
-use std::time::Duration;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use futures::channel::mpsc;
-use futures::{SinkExt, Stream, StreamExt};
+use futures::future::{self, Either};
+use futures::stream::FuturesUnordered;
+use futures::{FutureExt, SinkExt, Stream, StreamExt};
 use tokio::timer;
 use tonic::{Code, Request, Response, Status, Streaming};
 
-use crate::connection::{ConnectionMap, ConnectionReceiver};
+use crate::cluster::{Cluster, Leadership};
+use crate::connection::{
+    self, ConnectionMap, ConnectionReceiver, Handoff, QueueOpts, ResponseSender,
+};
+use crate::rebalance::RebalanceOpts;
+use crate::stats::Stats;
+use crate::store::{self, Store};
 use shardik::api::*;
 use shardik::resource::Resource;
+use shardik::ring::Ring;
+
+/// The client id admin requests which change a shard queue for it under.
+const ADMIN_CLIENT_ID: &str = "admin";
+/// The number of ids to try for the new shards when splitting a hot shard.
+const MAX_SPLIT_IDS: u32 = 1000;
 
 #[derive(Clone)]
 pub struct LockService {
-    connections: Arc<ConnectionMap>,
+    shards: Arc<HashMap<String, ShardData>>,
+    connections: Arc<Mutex<Arc<ConnectionMap>>>,
+    store: Arc<dyn Store>,
+    topology: Topology,
     latency: Duration,
+    lease: Duration,
+    /// How long a session lasts without hearing from the client.
+    session_ttl: Duration,
+    /// Ids for sessions, and for `lock` streams and admin requests outside of a session.
+    /// Requests with the same id come from the same client as far as deadlock detection is
+    /// concerned.
+    next_owner_id: Arc<AtomicU64>,
+    queue: QueueOpts,
+    stats: Arc<Stats>,
 }
 
 #[tonic::async_trait]
 impl server::LockService for LockService {
     type LockStream = mpsc::Receiver<Result<LockResponse, Status>>;
+    type SessionStream = mpsc::Receiver<Result<SessionResponse, Status>>;
 
     async fn lock(
         &self,
@@ -28,28 +59,1038 @@ impl server::LockService for LockService {
         let (response_tx, response_rx) = mpsc::channel(0);
 
         tokio::spawn(LockService::lock_handle_error(
+            self.clone(),
+            self.next_owner_id(),
+            request_rx,
+            response_tx,
+        ));
+        Ok(Response::new(response_rx))
+    }
+
+    async fn session(
+        &self,
+        request: Request<Streaming<SessionRequest>>,
+    ) -> Result<Response<Self::SessionStream>, Status> {
+        let request_rx = request.into_inner();
+        let (response_tx, response_rx) = mpsc::channel(0);
+
+        tokio::spawn(LockService::run_session(
             self.clone(),
             request_rx,
             response_tx,
         ));
         Ok(Response::new(response_rx))
     }
+
+    async fn leader(&self, _: Request<LeaderRequest>) -> Result<Response<LeaderResponse>, Status> {
+        let endpoint = match &self.topology {
+            Topology::Cluster(cluster) => match cluster.leader() {
+                Some(leader) => leader,
+                None => return Err(Status::new(Code::Unavailable, "no leader elected")),
+            },
+            _ => String::new(),
+        };
+        Ok(Response::new(LeaderResponse { endpoint }))
+    }
+
+    async fn queues(
+        &self,
+        request: Request<QueuesRequest>,
+    ) -> Result<Response<QueuesResponse>, Status> {
+        let connections = self.connections.lock().unwrap().clone();
+        let mut shard_ids = request.into_inner().shard_ids;
+        if shard_ids.is_empty() {
+            shard_ids = connections.shard_ids();
+        }
+
+        let shards = shard_ids
+            .into_iter()
+            .filter_map(|shard_id| {
+                let queue = connections.queue(&shard_id)?;
+                Some((shard_id, queue))
+            })
+            .collect();
+        Ok(Response::new(QueuesResponse { shards }))
+    }
+
+    async fn stats(&self, _: Request<StatsRequest>) -> Result<Response<StatsResponse>, Status> {
+        Ok(Response::new(self.stats.to_response()))
+    }
+
+    async fn cancel(
+        &self,
+        request: Request<CancelRequest>,
+    ) -> Result<Response<CancelResponse>, Status> {
+        let request = request.into_inner();
+        let connections = self.connections.lock().unwrap().clone();
+        match connections.cancel(&request.shard_id, &request.client_id) {
+            Some(cancelled) => {
+                log::info!(
+                    "Cancelled {} requests from {} for shard {}",
+                    cancelled,
+                    request.client_id,
+                    request.shard_id
+                );
+                Ok(Response::new(CancelResponse {
+                    cancelled: cancelled as u32,
+                }))
+            }
+            None => Err(Status::new(
+                Code::NotFound,
+                format!("shard {} not found", request.shard_id),
+            )),
+        }
+    }
+
+    async fn list_shards(
+        &self,
+        _: Request<ListShardsRequest>,
+    ) -> Result<Response<ListShardsResponse>, Status> {
+        let connections = self.connections.lock().unwrap().clone();
+        let shards = connections
+            .shard_ids()
+            .iter()
+            .filter_map(|shard_id| connections.info(shard_id))
+            .collect();
+        Ok(Response::new(ListShardsResponse { shards }))
+    }
+
+    async fn create_shard(
+        &self,
+        request: Request<CreateShardRequest>,
+    ) -> Result<Response<CreateShardResponse>, Status> {
+        let request = request.into_inner();
+        let shard_id = &request.shard_id;
+        if shard_id.is_empty() {
+            return Err(Status::new(Code::InvalidArgument, "shard id is empty"));
+        }
+        let connections = self.serve(shard_id)?;
+        check_keys_free(&connections, shard_id, &request.keys)?;
+        let data = ShardData {
+            locks: request
+                .keys
+                .iter()
+                .map(|key| (key.clone(), KeyLock::default()))
+                .collect(),
+            version: connections.next_version(),
+            ..ShardData::default()
+        };
+        if !connections.create(shard_id, data.clone()) {
+            return Err(Status::new(
+                Code::AlreadyExists,
+                format!("shard {} already exists", shard_id),
+            ));
+        }
+        if let Err(status) = self.record(&connections, shard_id, &data).await {
+            connections.remove(shard_id);
+            return Err(status);
+        }
+        log::info!("Created shard {} with {} keys", shard_id, data.locks.len());
+        Ok(Response::new(CreateShardResponse {}))
+    }
+
+    async fn delete_shard(
+        &self,
+        request: Request<DeleteShardRequest>,
+    ) -> Result<Response<DeleteShardResponse>, Status> {
+        let shard_id = request.into_inner().shard_id;
+        let connections = self.serve(&shard_id)?;
+        let owner = self.next_owner_id();
+        let (connection, data) = self
+            .hold(&connections, &shard_id, owner, Wait::Forever)
+            .await?;
+        if let Some(key) = locked_key(&data, data.locks.keys()) {
+            connection.response_tx.restore();
+            return Err(Status::new(
+                Code::FailedPrecondition,
+                format!("key {} in shard {} is locked", key, shard_id),
+            ));
+        }
+
+        let old_ids = [shard_id.clone()];
+        if let Err(status) = self.replace_shards(&connections, &old_ids, &[]).await {
+            connection.response_tx.restore();
+            return Err(status);
+        }
+        log::info!("Deleted shard {}", shard_id);
+        Ok(Response::new(DeleteShardResponse {}))
+    }
+
+    async fn add_keys(
+        &self,
+        request: Request<KeysRequest>,
+    ) -> Result<Response<KeysResponse>, Status> {
+        let changed = self.change_keys(request.into_inner(), true).await?;
+        Ok(Response::new(KeysResponse { changed }))
+    }
+
+    async fn remove_keys(
+        &self,
+        request: Request<KeysRequest>,
+    ) -> Result<Response<KeysResponse>, Status> {
+        let changed = self.change_keys(request.into_inner(), false).await?;
+        Ok(Response::new(KeysResponse { changed }))
+    }
+
+    async fn split_shard(
+        &self,
+        request: Request<SplitShardRequest>,
+    ) -> Result<Response<SplitShardResponse>, Status> {
+        let request = request.into_inner();
+        let connections = self.serve(&request.shard_id)?;
+        let new_ids = [request.left_id.as_str(), request.right_id.as_str()];
+        self.check_new_shards(&connections, &new_ids)?;
+        self.split(&connections, request, Wait::Forever).await?;
+        Ok(Response::new(SplitShardResponse {}))
+    }
+
+    async fn merge_shards(
+        &self,
+        request: Request<MergeShardsRequest>,
+    ) -> Result<Response<MergeShardsResponse>, Status> {
+        let request = request.into_inner();
+        let mut shard_ids = request.shard_ids;
+        shard_ids.sort();
+        shard_ids.dedup();
+        if shard_ids.len() < 2 {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                "at least two shards are needed to merge",
+            ));
+        }
+        let connections = match self.route_many(&shard_ids)? {
+            Route::Serve(connections) => connections,
+            Route::Redirect(redirect) => {
+                return Err(Status::new(
+                    Code::FailedPrecondition,
+                    format!("the shards are served by {}", redirect.endpoint),
+                ))
+            }
+        };
+        self.check_new_shards(&connections, &[request.new_shard_id.as_str()])?;
+
+        // Hold the shards in sorted order, like clients acquiring several shards at once, so
+        // that we can't deadlock with them.
+        let owner = self.next_owner_id();
+        let mut held = Vec::with_capacity(shard_ids.len());
+        let mut merged = ShardData::default();
+        for shard_id in &shard_ids {
+            match self
+                .hold(&connections, shard_id, owner, Wait::Forever)
+                .await
+            {
+                Ok((connection, data)) => {
+                    merged.locks.extend(data.locks);
+                    held.push(connection);
+                }
+                Err(status) => {
+                    for connection in held {
+                        connection.response_tx.restore();
+                    }
+                    return Err(status);
+                }
+            }
+        }
+        log::info!(
+            "Merging shards {:?} into {} ({} keys)",
+            shard_ids,
+            request.new_shard_id,
+            merged.locks.len()
+        );
+
+        merged.version = connections.next_version();
+        let new = [(request.new_shard_id, merged)];
+        if let Err(status) = self.replace_shards(&connections, &shard_ids, &new).await {
+            for connection in held {
+                connection.response_tx.restore();
+            }
+            return Err(status);
+        }
+        Ok(Response::new(MergeShardsResponse {}))
+    }
+
+    async fn routes(&self, _: Request<RoutesRequest>) -> Result<Response<RoutesResponse>, Status> {
+        let connections = self.connections.lock().unwrap().clone();
+        Ok(Response::new(connections.routes()))
+    }
+}
+
+/// How this server relates to any other servers.
+#[derive(Clone)]
+pub enum Topology {
+    Standalone,
+    /// One of a cluster of servers replicating every shard, where only the leader serves
+    /// requests.
+    Cluster(Arc<Cluster>),
+    /// One of several servers which each own the shards assigned to them by `ring`.
+    Partitioned {
+        id: String,
+        ring: Ring,
+    },
+}
+
+/// Something which happened in a session.
+enum SessionEvent {
+    Request(Result<SessionRequest, Status>),
+    /// The request with the given id has finished.
+    Finished(u64),
+}
+
+/// Where to handle a lock request.
+enum Route {
+    Serve(Arc<ConnectionMap>),
+    Redirect(Redirect),
 }
 
 impl LockService {
-    pub fn new<R: Resource>(resource: &R, latency: Duration) -> Self {
-        LockService {
-            connections: Arc::new(ConnectionMap::new(resource)),
+    pub fn new<R: Resource>(
+        resource: &R,
+        store: Arc<dyn Store>,
+        topology: Topology,
+        latency: Duration,
+        lease: Duration,
+        session_ttl: Duration,
+        queue: QueueOpts,
+    ) -> io::Result<Self> {
+        let mut shards = connection::initial_shards(resource);
+        if let Topology::Partitioned { id, ring } = &topology {
+            shards.retain(|shard_id, _| ring.node(shard_id) == id.as_str());
+            log::info!("Serving {} shards", shards.len());
+        }
+        let stats = Arc::new(Stats::default());
+        let connections = ConnectionMap::new(&shards, &store.load()?, 0, queue, stats.clone());
+        Ok(LockService {
+            shards: Arc::new(shards),
+            connections: Arc::new(Mutex::new(Arc::new(connections))),
+            store,
+            topology,
             latency,
+            lease,
+            session_ttl,
+            next_owner_id: Arc::new(AtomicU64::new(1)),
+            queue,
+            stats,
+        })
+    }
+
+    /// Splits any shards which have become hot since the last check, every rebalance
+    /// interval. Clients waiting for a split shard are sent the new routes, and retry on the
+    /// shard their key has moved to.
+    ///
+    /// The new routes aren't pushed to anyone else. Other clients only learn of them when
+    /// they next ask for a shard which has gone, or are granted a shard with a routes version
+    /// different from theirs.
+    pub async fn rebalance(self, opts: RebalanceOpts) {
+        let interval = match opts.interval() {
+            Some(interval) => interval,
+            None => return,
+        };
+        let mut ticks = timer::Interval::new_interval(interval);
+        let mut last_tick = Instant::now();
+        while ticks.next().await.is_some() {
+            let now = Instant::now();
+            let elapsed = now - last_tick;
+            last_tick = now;
+
+            let connections = self.connections.lock().unwrap().clone();
+            for (shard_id, load) in connections.take_load() {
+                // A shard with a single key can't be split, however busy it is.
+                let keys = connections
+                    .info(&shard_id)
+                    .map_or(0, |info| info.keys.len());
+                if keys < 2 || !opts.is_hot(&load, elapsed) {
+                    continue;
+                }
+                // Don't hold up the other hot shards waiting for one which is held for a long
+                // time. It will still be hot at the next check if it needs splitting.
+                if let Err(status) = self
+                    .split_hot(&connections, &shard_id, Wait::For(interval))
+                    .await
+                {
+                    if status.code() == Code::DeadlineExceeded {
+                        log::info!("Skipped splitting hot shard {}, which is held", shard_id);
+                    } else {
+                        log::warn!(
+                            "Failed to split hot shard {}: {}",
+                            shard_id,
+                            status.message()
+                        );
+                    }
+                    continue;
+                }
+                log::info!(
+                    "Split hot shard {}, which was handed on {} times in {:?} with a total \
+                     wait of {:?}",
+                    shard_id,
+                    load.handoffs,
+                    elapsed,
+                    load.wait
+                );
+                self.stats.split_hot_shard();
+            }
         }
     }
 
+    /// Splits a hot shard in half, naming the new shards after it. Fails with
+    /// `DeadlineExceeded` if the shard can't be held for the split within `wait`.
+    async fn split_hot(
+        &self,
+        connections: &Arc<ConnectionMap>,
+        shard_id: &str,
+        wait: Wait,
+    ) -> Result<(), Status> {
+        // Only the server responsible for the shard may split it, and when shards are
+        // partitioned the new shards must belong to this server too.
+        self.serve(shard_id)?;
+        let mut new_ids = (0..MAX_SPLIT_IDS)
+            .map(|index| format!("{}.{}", shard_id, index))
+            .filter(|id| !connections.contains(id) && self.serve(id).is_ok());
+        let (left_id, right_id) = match (new_ids.next(), new_ids.next()) {
+            (Some(left_id), Some(right_id)) => (left_id, right_id),
+            _ => {
+                return Err(Status::new(
+                    Code::ResourceExhausted,
+                    "no free shard ids to split into",
+                ))
+            }
+        };
+        let request = SplitShardRequest {
+            shard_id: shard_id.to_owned(),
+            left_id,
+            right_id,
+            left_keys: Vec::new(),
+        };
+        self.split(connections, request, wait).await
+    }
+
+    /// Moves a shard's keys into two new shards, which must have been checked with
+    /// `check_new_shards`.
+    async fn split(
+        &self,
+        connections: &Arc<ConnectionMap>,
+        request: SplitShardRequest,
+        wait: Wait,
+    ) -> Result<(), Status> {
+        let shard_id = &request.shard_id;
+        let owner = self.next_owner_id();
+        let (connection, data) = self.hold(connections, shard_id, owner, wait).await?;
+
+        let left_keys: HashSet<String> = if request.left_keys.is_empty() {
+            let mut keys: Vec<String> = data.locks.keys().cloned().collect();
+            keys.sort();
+            keys.truncate(keys.len() / 2);
+            keys.into_iter().collect()
+        } else {
+            if let Some(key) = request
+                .left_keys
+                .iter()
+                .find(|key| !data.locks.contains_key(*key))
+            {
+                connection.response_tx.restore();
+                return Err(Status::new(
+                    Code::InvalidArgument,
+                    format!("key {} is not in shard {}", key, shard_id),
+                ));
+            }
+            request.left_keys.into_iter().collect()
+        };
+        let (left, right): (HashMap<_, _>, HashMap<_, _>) = data
+            .locks
+            .into_iter()
+            .partition(|(key, _)| left_keys.contains(key));
+        log::info!(
+            "Splitting shard {} into {} ({} keys) and {} ({} keys)",
+            shard_id,
+            request.left_id,
+            left.len(),
+            request.right_id,
+            right.len()
+        );
+
+        let left = ShardData {
+            locks: left,
+            version: connections.next_version(),
+            ..ShardData::default()
+        };
+        let right = ShardData {
+            locks: right,
+            version: connections.next_version(),
+            ..ShardData::default()
+        };
+        let new = [(request.left_id, left), (request.right_id, right)];
+        let old_ids = [shard_id.clone()];
+        if let Err(status) = self.replace_shards(connections, &old_ids, &new).await {
+            connection.response_tx.restore();
+            return Err(status);
+        }
+        Ok(())
+    }
+
+    /// Gets the shards to serve a request for `shard_id` from, or the server to redirect the
+    /// client to if this server is not responsible for it.
+    fn route(&self, shard_id: &str) -> Result<Route, Status> {
+        let cluster = match &self.topology {
+            Topology::Standalone => {
+                return Ok(Route::Serve(self.connections.lock().unwrap().clone()))
+            }
+            Topology::Cluster(cluster) => cluster,
+            Topology::Partitioned { id, ring } => {
+                let node = ring.node(shard_id);
+                if node != id.as_str() {
+                    return Ok(Route::Redirect(Redirect {
+                        endpoint: node.to_owned(),
+                        reason: redirect::Reason::WrongNode as i32,
+                    }));
+                }
+                return Ok(Route::Serve(self.connections.lock().unwrap().clone()));
+            }
+        };
+
+        match cluster.leadership() {
+            Leadership::Leader(term) => {
+                let mut connections = self.connections.lock().unwrap();
+                if connections.generation() != term {
+                    // Start again from the replicated state. Any requests still being handled
+                    // for an earlier term will fail when they try to record their changes.
+                    log::info!("Restoring shards for term {}", term);
+                    *connections = Arc::new(ConnectionMap::new(
+                        &self.shards,
+                        &cluster.shards(),
+                        term,
+                        self.queue,
+                        self.stats.clone(),
+                    ));
+                }
+                Ok(Route::Serve(connections.clone()))
+            }
+            Leadership::Follower(Some(leader)) => Ok(Route::Redirect(Redirect {
+                endpoint: leader,
+                reason: redirect::Reason::NotLeader as i32,
+            })),
+            Leadership::Follower(None) => Err(Status::new(Code::Unavailable, "no leader elected")),
+        }
+    }
+
+    /// Gets the shards to handle an admin request for `shard_id` with. Admin requests aren't
+    /// redirected, since the caller is expected to ask the right server.
+    fn serve(&self, shard_id: &str) -> Result<Arc<ConnectionMap>, Status> {
+        match self.route(shard_id)? {
+            Route::Serve(connections) => Ok(connections),
+            Route::Redirect(redirect) => Err(Status::new(
+                Code::FailedPrecondition,
+                format!("shard {} is served by {}", shard_id, redirect.endpoint),
+            )),
+        }
+    }
+
+    /// Like `route`, but for several shards which must all be handled by the same server.
+    fn route_many(&self, shard_ids: &[String]) -> Result<Route, Status> {
+        let mut routes = shard_ids.iter().map(|shard_id| self.route(shard_id));
+        let first = match routes.next() {
+            Some(route) => route?,
+            None => return Err(Status::new(Code::InvalidArgument, "no shards requested")),
+        };
+        for route in routes {
+            match (&first, route?) {
+                (Route::Serve(_), Route::Serve(_)) => (),
+                (Route::Redirect(first), Route::Redirect(redirect))
+                    if first.endpoint == redirect.endpoint =>
+                {
+                    ()
+                }
+                _ => {
+                    return Err(Status::new(
+                        Code::InvalidArgument,
+                        "the shards are not all served by the same server",
+                    ))
+                }
+            }
+        }
+        Ok(first)
+    }
+
+    /// Waits to be granted a shard, or returns `None` if the client goes away first.
+    async fn begin(
+        connections: &Arc<ConnectionMap>,
+        request: &mut (impl Stream<Item = Result<LockRequest, Status>> + Unpin),
+        shard_id: &str,
+        options: &Acquire,
+        owner: u64,
+        wait: Wait,
+    ) -> Result<Option<(ConnectionReceiver, Handoff)>, Status> {
+        // If the client goes away while waiting, give up its place in the queue.
+        let begin = connections.clone().begin(
+            shard_id,
+            &options.client_id,
+            owner,
+            options.mode(),
+            options.priority,
+            wait,
+        );
+        futures::pin_mut!(begin);
+        loop {
+            let req = match future::select(begin.as_mut(), request.next()).await {
+                Either::Left((result, _)) => return result.map(Some),
+                Either::Right((req, _)) => req,
+            };
+            match req {
+                // Session heartbeats renew every request in the session, including those
+                // still waiting.
+                Some(Ok(LockRequest {
+                    body: Some(lock_request::Body::Renew(_)),
+                })) => continue,
+                Some(Err(status)) => return Err(status),
+                Some(Ok(_)) => {
+                    return Err(Status::new(
+                        Code::FailedPrecondition,
+                        "unexpected request before `acquired`",
+                    ))
+                }
+                None => {
+                    log::info!("Client cancelled acquire request for shard {}", shard_id);
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    /// Describes a shard granted to a client. If the client already has an earlier version
+    /// of the data, it is sent only the changes since then, as long as the shard's history
+    /// goes back that far.
+    fn acquired(
+        &self,
+        connections: &ConnectionMap,
+        shard_id: &str,
+        handoff: Handoff,
+        known_version: u64,
+    ) -> Acquired {
+        let data = match connections.delta(shard_id, known_version) {
+            Some(delta) => {
+                log::debug!(
+                    "Sending {} changed keys for shard {} since version {}",
+                    delta.locks.len() + delta.removed_keys.len(),
+                    shard_id,
+                    known_version
+                );
+                self.stats.send_delta();
+                delta
+            }
+            None => {
+                if known_version != 0 {
+                    self.stats.resync();
+                }
+                handoff.data
+            }
+        };
+        Acquired {
+            data: Some(data),
+            lease_millis: self.lease.as_millis() as u64,
+            unclean: handoff.unclean,
+            routes_version: connections.routes_version(),
+        }
+    }
+
+    /// Gives up a shard released by a client. Data released by an exclusive holder may be a
+    /// delta from the data it was granted, and becomes the next version of the shard's data.
+    async fn release(
+        &self,
+        connections: &ConnectionMap,
+        shard_id: &str,
+        mode: Mode,
+        data: ShardData,
+        response_tx: ResponseSender,
+    ) -> Result<(), Status> {
+        if mode == Mode::Shared {
+            response_tx.send(data);
+            return Ok(());
+        }
+        let mut data = connections.resolve(shard_id, data)?;
+        data.stamp_expiries();
+        data.version = connections.next_version();
+        self.record(connections, shard_id, &data).await?;
+        response_tx.send(data);
+        Ok(())
+    }
+
+    /// When to send the response granting a shard, after the simulated latency. If the shard
+    /// was handed over from holders which had to be asked to release it, the latency counts
+    /// from when they were asked, so that the grant overlaps their release round trip rather
+    /// than following it, and a handoff only pays the latency once.
+    fn respond_at(&self, handoff: &Handoff) -> Instant {
+        match handoff.asked_at {
+            Some(asked_at) if !self.queue.serial_handoffs() => asked_at + self.latency,
+            _ => Instant::now() + self.latency,
+        }
+    }
+
+    /// Takes a new owner id, for a session or for requests outside of one.
+    fn next_owner_id(&self) -> u64 {
+        self.next_owner_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Acquires a shard exclusively for an admin request, queueing ahead of any clients
+    /// waiting for it, but behind its current holders, for as long as `wait` allows. An
+    /// admin request holding several shards uses the same `owner` for each, so it takes part
+    /// in deadlock detection like any other client.
+    async fn hold(
+        &self,
+        connections: &Arc<ConnectionMap>,
+        shard_id: &str,
+        owner: u64,
+        wait: Wait,
+    ) -> Result<(ConnectionReceiver, ShardData), Status> {
+        let (connection, handoff) = connections
+            .clone()
+            .begin(
+                shard_id,
+                ADMIN_CLIENT_ID,
+                owner,
+                Mode::Exclusive,
+                u32::max_value(),
+                wait,
+            )
+            .await?;
+        Ok((connection, handoff.data))
+    }
+
+    /// Checks that new shards with the given ids can be created on this server.
+    fn check_new_shards(
+        &self,
+        connections: &ConnectionMap,
+        shard_ids: &[&str],
+    ) -> Result<(), Status> {
+        for (index, shard_id) in shard_ids.iter().enumerate() {
+            if shard_id.is_empty() {
+                return Err(Status::new(Code::InvalidArgument, "shard id is empty"));
+            }
+            if shard_ids[..index].contains(shard_id) {
+                return Err(Status::new(
+                    Code::InvalidArgument,
+                    format!("shard id {} given more than once", shard_id),
+                ));
+            }
+            self.serve(shard_id)?;
+            if connections.contains(shard_id) {
+                return Err(Status::new(
+                    Code::AlreadyExists,
+                    format!("shard {} already exists", shard_id),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces shards held by an admin request with new ones. If this fails nothing is
+    /// changed, and the caller should give back the shards it holds. Otherwise the old
+    /// shards are gone, and anyone waiting for them fails with `NotFound`.
+    async fn replace_shards(
+        &self,
+        connections: &ConnectionMap,
+        old_ids: &[String],
+        new: &[(String, ShardData)],
+    ) -> Result<(), Status> {
+        for (index, (shard_id, data)) in new.iter().enumerate() {
+            if !connections.create(shard_id, data.clone()) {
+                for (shard_id, _) in &new[..index] {
+                    connections.remove(shard_id);
+                }
+                return Err(Status::new(
+                    Code::AlreadyExists,
+                    format!("shard {} already exists", shard_id),
+                ));
+            }
+        }
+
+        // Record the new shards and delete the old ones in one entry, so that the server
+        // never restarts with only some of the changes, losing or duplicating keys.
+        let deleted = ShardData {
+            deleted: true,
+            version: connections.next_version(),
+            ..ShardData::default()
+        };
+        let changes: Vec<_> = new
+            .iter()
+            .map(|(shard_id, data)| (shard_id.as_str(), data))
+            .chain(old_ids.iter().map(|shard_id| (shard_id.as_str(), &deleted)))
+            .collect();
+        if let Err(status) = self.record_all(connections, &changes).await {
+            for (shard_id, _) in new {
+                connections.remove(shard_id);
+            }
+            return Err(status);
+        }
+        for shard_id in old_ids {
+            connections.remove(shard_id);
+        }
+        Ok(())
+    }
+
+    /// Adds keys to or removes keys from a shard, returning how many were changed.
+    async fn change_keys(&self, request: KeysRequest, add: bool) -> Result<u32, Status> {
+        let shard_id = &request.shard_id;
+        let connections = self.serve(shard_id)?;
+        let owner = self.next_owner_id();
+        let (connection, mut data) = self
+            .hold(&connections, shard_id, owner, Wait::Forever)
+            .await?;
+        if add {
+            if let Err(status) = check_keys_free(&connections, shard_id, &request.keys) {
+                connection.response_tx.restore();
+                return Err(status);
+            }
+        } else if let Some(key) = locked_key(&data, &request.keys) {
+            connection.response_tx.restore();
+            return Err(Status::new(
+                Code::FailedPrecondition,
+                format!("key {} in shard {} is locked", key, shard_id),
+            ));
+        }
+
+        let mut changed = 0;
+        for key in &request.keys {
+            if add && !data.locks.contains_key(key) {
+                data.locks.insert(key.clone(), KeyLock::default());
+                changed += 1;
+            } else if !add && data.locks.remove(key).is_some() {
+                changed += 1;
+            }
+        }
+        if changed == 0 {
+            connection.response_tx.restore();
+            return Ok(0);
+        }
+        data.version = connections.next_version();
+        if let Err(status) = self.record(&connections, shard_id, &data).await {
+            connection.response_tx.restore();
+            return Err(status);
+        }
+        connections.change_routes();
+        log::info!(
+            "{} {} keys {} shard {}",
+            if add { "Added" } else { "Removed" },
+            changed,
+            if add { "to" } else { "from" },
+            shard_id
+        );
+        connection.response_tx.send(data);
+        Ok(changed)
+    }
+
+    /// Records the latest data for a shard. In a cluster this fails if the server is no
+    /// longer the leader for the term `connections` was built for. Otherwise failures are
+    /// only logged, since it is better to keep handing out the shard than to lose it.
+    async fn record(
+        &self,
+        connections: &ConnectionMap,
+        shard_id: &str,
+        data: &ShardData,
+    ) -> Result<(), Status> {
+        self.record_all(connections, &[(shard_id, data)]).await
+    }
+
+    /// Records the latest data for several shards in a single entry, so that either all of
+    /// the changes are restored when the server restarts or none are.
+    async fn record_all(
+        &self,
+        connections: &ConnectionMap,
+        changes: &[(&str, &ShardData)],
+    ) -> Result<(), Status> {
+        let entry = store::entry(changes);
+        if let Topology::Cluster(cluster) = &self.topology {
+            return cluster
+                .clone()
+                .propose(connections.generation(), entry)
+                .await;
+        }
+
+        if let Err(err) = self.store.record(entry).await {
+            let shard_ids: Vec<_> = changes.iter().map(|(shard_id, _)| shard_id).collect();
+            log::error!("Failed to record data for shards {:?}: {}", shard_ids, err);
+        }
+        Ok(())
+    }
+
+    /// Handles a session. It starts with the client registering, and ends when the client
+    /// closes it, or expires if the client goes quiet for longer than the session TTL.
+    ///
+    /// The requests in the session are all handled on this task. Each one is handled just
+    /// like a request on its own `lock` stream, which is fed the messages tagged with its id,
+    /// and its responses are tagged with its id in turn. When the session ends, so do its
+    /// requests, and any shards they hold are handed on as if their streams had been closed.
+    pub async fn run_session(
+        self,
+        request: impl Stream<Item = Result<SessionRequest, Status>>,
+        mut response: mpsc::Sender<Result<SessionResponse, Status>>,
+    ) {
+        futures::pin_mut!(request);
+        let client_id = match request.next().await {
+            Some(Ok(SessionRequest {
+                register: Some(register),
+                ..
+            })) => register.client_id,
+            Some(Ok(_)) => {
+                let status = Status::new(
+                    Code::FailedPrecondition,
+                    "expected request to be `register`",
+                );
+                let _ = response.send(Err(status)).await;
+                return;
+            }
+            _ => return,
+        };
+        let session_id = self.next_owner_id();
+        log::info!("Registered session {} for {}", session_id, client_id);
+        // Clients can't send heartbeats any more often than every millisecond.
+        let heartbeat_interval = cmp::max(
+            cmp::min(self.session_ttl, self.lease) / 3,
+            Duration::from_millis(1),
+        );
+        let registered = Registered {
+            session_id,
+            ttl_millis: self.session_ttl.as_millis() as u64,
+            heartbeat_interval_millis: heartbeat_interval.as_millis() as u64,
+        };
+        let sent = response
+            .send(Ok(SessionResponse {
+                request_id: 0,
+                body: Some(session_response::Body::Registered(registered)),
+            }))
+            .await;
+        if sent.is_err() {
+            return;
+        }
+
+        let mut requests = HashMap::new();
+        let mut running = FuturesUnordered::new();
+        // Anything the client sends keeps the session alive, not just heartbeats.
+        let mut expires_at = Instant::now() + self.session_ttl;
+        loop {
+            // `running` would finish straight away if it was polled while empty.
+            let finished = if running.is_empty() {
+                Either::Left(future::pending())
+            } else {
+                Either::Right(running.next())
+            };
+            let event = future::select(request.next(), finished).map(|event| match event {
+                Either::Left((req, _)) => req.map(SessionEvent::Request),
+                Either::Right((request_id, _)) => request_id.map(SessionEvent::Finished),
+            });
+            let req = match timer::Timeout::new_at(event, expires_at).await {
+                Ok(Some(SessionEvent::Request(Ok(req)))) => {
+                    expires_at = Instant::now() + self.session_ttl;
+                    req
+                }
+                Ok(Some(SessionEvent::Request(Err(status)))) => {
+                    log::warn!("Session {} failed: {}", session_id, status);
+                    break;
+                }
+                Ok(Some(SessionEvent::Finished(request_id))) => {
+                    requests.remove(&request_id);
+                    continue;
+                }
+                Ok(None) => {
+                    log::info!("Session {} closed", session_id);
+                    break;
+                }
+                Err(_) => {
+                    log::warn!(
+                        "Session {} for {} expired, reclaiming {} requests",
+                        session_id,
+                        client_id,
+                        requests.len()
+                    );
+                    let status = Status::new(
+                        Code::DeadlineExceeded,
+                        format!("session {} expired", session_id),
+                    );
+                    let _ = response.send(Err(status)).await;
+                    break;
+                }
+            };
+
+            if req.heartbeat {
+                log::debug!("Received heartbeat for session {}", session_id);
+                for request_tx in requests.values() {
+                    let renew = LockRequest {
+                        body: Some(lock_request::Body::Renew(String::new())),
+                    };
+                    let _ = request_tx.unbounded_send(Ok(renew));
+                }
+                continue;
+            }
+            for release in req.releases {
+                match (requests.get(&release.request_id), release.request) {
+                    // Fails if the request has just finished, which is fine.
+                    (Some(request_tx), Some(lock_request)) => {
+                        let _ = request_tx.unbounded_send(Ok(lock_request));
+                    }
+                    _ => log::debug!(
+                        "Ignoring release for finished request {}",
+                        release.request_id
+                    ),
+                }
+            }
+            let request_id = req.request_id;
+            if req.end {
+                requests.remove(&request_id);
+                continue;
+            }
+            let lock_request = match req.request {
+                Some(lock_request) => lock_request,
+                None => continue,
+            };
+            if let Some(request_tx) = requests.get(&request_id) {
+                // Fails if the request has just finished, which is fine.
+                let _ = request_tx.unbounded_send(Ok(lock_request));
+                continue;
+            }
+            match &lock_request.body {
+                Some(lock_request::Body::Acquire(_)) | Some(lock_request::Body::AcquireMany(_)) => {
+                }
+                _ => {
+                    // A release crossing with the end of the request, for example.
+                    log::debug!("Ignoring message for finished request {}", request_id);
+                    continue;
+                }
+            }
+
+            let (request_tx, request_rx) = mpsc::unbounded();
+            let _ = request_tx.unbounded_send(Ok(lock_request));
+            requests.insert(request_id, request_tx);
+            running.push(self.clone().session_request(
+                session_id,
+                request_id,
+                request_rx,
+                response.clone(),
+            ));
+        }
+    }
+
+    /// Handles one of the requests in a session, returning its id once it has finished.
+    async fn session_request(
+        self,
+        session_id: u64,
+        request_id: u64,
+        request: mpsc::UnboundedReceiver<Result<LockRequest, Status>>,
+        response: mpsc::Sender<Result<SessionResponse, Status>>,
+    ) -> u64 {
+        let (lock_tx, lock_rx) = mpsc::channel(0);
+        future::join(
+            self.lock_handle_error(session_id, request, lock_tx),
+            forward_responses(request_id, lock_rx, response),
+        )
+        .await;
+        request_id
+    }
+
+    /// Handles a request for a shard, or several, from the client `owner`.
     pub async fn lock_handle_error(
         self,
+        owner: u64,
         request: impl Stream<Item = Result<LockRequest, Status>>,
         mut response: mpsc::Sender<Result<LockResponse, Status>>,
     ) {
-        if let Err(status) = self.lock_inner(request, response.clone()).await {
+        if let Err(status) = self.lock_inner(owner, request, response.clone()).await {
             log::error!("Sending error response: {}", status);
             let _ = response.send(Err(status)).await;
         }
@@ -57,62 +1098,416 @@ impl LockService {
 
     pub async fn lock_inner(
         self,
+        owner: u64,
         request: impl Stream<Item = Result<LockRequest, Status>>,
         mut response: mpsc::Sender<Result<LockResponse, Status>>,
     ) -> Result<(), Status> {
         futures::pin_mut!(request);
         let latency = self.latency;
 
-        let shard_id = match request.next().await {
-            Some(req) => req?.expect_acquire()?,
+        let acquire = match request.next().await {
+            Some(req) => match req?.body {
+                Some(lock_request::Body::AcquireMany(acquire)) => {
+                    return self.lock_many(owner, acquire, request, response).await;
+                }
+                body => LockRequest { body }.expect_acquire()?,
+            },
             None => return Ok(()),
         };
-        log::info!("Received acquire request for shard {}", shard_id);
-        let (connection, data) = match self.connections.begin(&shard_id).await {
-            Some(result) => result,
-            None => return Err(Status::new(Code::NotFound, "key not found")),
+        let mode = acquire.mode();
+        let shard_id = acquire.shard_id.clone();
+        log::info!(
+            "Received {:?} acquire request for shard {} from {} (priority {})",
+            mode,
+            shard_id,
+            acquire.client_id,
+            acquire.priority
+        );
+        let connections = match self.route(&shard_id)? {
+            Route::Serve(connections) => connections,
+            Route::Redirect(redirect) => {
+                log::info!(
+                    "Redirecting request for shard {} to {}",
+                    shard_id,
+                    redirect.endpoint
+                );
+                send_redirect(&mut response, redirect).await;
+                return Ok(());
+            }
+        };
+        let wait = acquire.wait();
+        let grant =
+            LockService::begin(&connections, &mut request, &shard_id, &acquire, owner, wait);
+        let (connection, handoff) = match grant.await {
+            Ok(Some(grant)) => grant,
+            Ok(None) => return Ok(()),
+            Err(ref status) if status.code() == Code::NotFound => {
+                log::info!("Shard {} not found, sending routes", shard_id);
+                send_routes(&mut response, &connections).await;
+                return Ok(());
+            }
+            Err(status) => return Err(status),
         };
-        timer::delay_for(latency).await;
+        if handoff.unclean {
+            log::warn!("Shard {} was recovered after an unclean handoff", shard_id);
+        }
+        // Shared holders can't change the data, so only exclusive grants need recording. This
+        // also records the new fencing token, so it isn't handed out again after a restart.
+        if mode == Mode::Exclusive {
+            if let Err(status) = self.record(&connections, &shard_id, &handoff.data).await {
+                // The client never received the shard, so give it back unchanged.
+                connection.response_tx.restore();
+                return Err(status);
+            }
+        }
+        timer::delay(self.respond_at(&handoff)).await;
         log::info!("Sending acquired response for shard {}", shard_id);
-        response
+        let sent = response
             .send(Ok(LockResponse {
-                body: Some(lock_response::Body::Acquired(data)),
+                body: Some(lock_response::Body::Acquired(self.acquired(
+                    &connections,
+                    &shard_id,
+                    handoff,
+                    acquire.known_version,
+                ))),
             }))
-            .await
-            .unwrap();
-
-        tokio::spawn(ConnectionReceiver::request_release(
-            connection.request_rx,
-            move |shard_id| {
-                async move {
-                    timer::delay_for(latency).await;
-                    log::info!("Sending release response for shard {}", shard_id);
-                    let _ = response
-                        .send(Ok(LockResponse {
-                            body: Some(lock_response::Body::Release(shard_id)),
-                        }))
-                        .await;
+            .await;
+        if sent.is_err() {
+            log::warn!("Client disconnected before receiving shard {}", shard_id);
+            connection.response_tx.restore();
+            return Ok(());
+        }
+
+        let release_requested =
+            ConnectionReceiver::request_release(connection.request_rx, move |shard_id| {
+                send_release(response, latency, shard_id)
+            })
+            .fuse();
+        futures::pin_mut!(release_requested);
+
+        // Wait for the client to release the shard, renewing the lease whenever asked and
+        // asking the client to release it when someone else wants it. If the lease runs out,
+        // or the client goes away, `connection.response_tx` is dropped and the shard is
+        // reclaimed with the data it was granted with.
+        let mut lease = timer::delay_for(self.lease);
+        let data = loop {
+            let deadline = future::select(&mut lease, &mut release_requested);
+            let req = match future::select(request.next(), deadline).await {
+                Either::Left((req, _)) => req,
+                Either::Right((Either::Left(_), _)) => {
+                    log::warn!("Lease expired for shard {}", shard_id);
+                    return Err(Status::new(
+                        Code::DeadlineExceeded,
+                        format!("lease on shard {} expired", shard_id),
+                    ));
                 }
-            },
-        ));
+                Either::Right((Either::Right(_), _)) => continue,
+            };
 
-        let data = match request.next().await {
-            Some(Ok(req)) => req.expect_released()?,
-            _ => {
-                return Err(Status::new(
-                    Code::DataLoss,
-                    format!("shard {} not released", shard_id),
-                ));
+            match req {
+                Some(Ok(LockRequest {
+                    body: Some(lock_request::Body::Renew(_)),
+                })) => {
+                    log::debug!("Renewing lease for shard {}", shard_id);
+                    lease.reset(Instant::now() + self.lease);
+                }
+                Some(Ok(req)) => break req.expect_released()?,
+                _ => {
+                    return Err(Status::new(
+                        Code::DataLoss,
+                        format!("shard {} not released", shard_id),
+                    ));
+                }
             }
         };
         log::info!("Received released request for shard {}", shard_id);
-        connection.response_tx.send(data).unwrap();
+        self.release(&connections, &shard_id, mode, data, connection.response_tx)
+            .await?;
+        Ok(())
+    }
+
+    /// Handles a request for several shards at once. The shards are acquired one at a time
+    /// in sorted order, and any already acquired are given back if one can't be.
+    async fn lock_many(
+        &self,
+        owner: u64,
+        acquire: AcquireMany,
+        mut request: impl Stream<Item = Result<LockRequest, Status>> + Unpin,
+        mut response: mpsc::Sender<Result<LockResponse, Status>>,
+    ) -> Result<(), Status> {
+        let latency = self.latency;
+        let options = acquire.options.unwrap_or_default();
+        let known_versions = acquire.known_versions;
+        let mode = options.mode();
+        let mut shard_ids = acquire.shard_ids;
+        shard_ids.sort();
+        shard_ids.dedup();
+        log::info!(
+            "Received {:?} acquire request for shards {:?} from {} (priority {})",
+            mode,
+            shard_ids,
+            options.client_id,
+            options.priority
+        );
+        let connections = match self.route_many(&shard_ids)? {
+            Route::Serve(connections) => connections,
+            Route::Redirect(redirect) => {
+                log::info!(
+                    "Redirecting request for shards {:?} to {}",
+                    shard_ids,
+                    redirect.endpoint
+                );
+                send_redirect(&mut response, redirect).await;
+                return Ok(());
+            }
+        };
+
+        // The time allowed by the client is for all of the shards together.
+        let deadline = match options.wait() {
+            Wait::For(timeout) => Some(Instant::now() + timeout),
+            _ => None,
+        };
+        let mut grants = Vec::with_capacity(shard_ids.len());
+        for shard_id in &shard_ids {
+            let wait = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    Wait::For(if deadline > now {
+                        deadline - now
+                    } else {
+                        Duration::from_secs(0)
+                    })
+                }
+                None => options.wait(),
+            };
+            let grant =
+                LockService::begin(&connections, &mut request, shard_id, &options, owner, wait);
+            match grant.await {
+                Ok(Some(grant)) => grants.push(grant),
+                result => {
+                    for (connection, _) in grants {
+                        connection.response_tx.restore();
+                    }
+                    return match result {
+                        Err(ref status) if status.code() == Code::NotFound => {
+                            log::info!("Shard {} not found, sending routes", shard_id);
+                            send_routes(&mut response, &connections).await;
+                            Ok(())
+                        }
+                        result => result.map(|_| ()),
+                    };
+                }
+            }
+        }
 
-        // if let Some(req) = request.next().await {
-        //     log::error!("unexpected message {:?}", req);
-        //     return Err(Status::new(Code::FailedPrecondition, "connection closed"));
-        // }
+        // Like a single shard, only exclusive grants need recording. They are recorded together,
+        // and if that fails the client never receives any of them, so they are given back.
+        if mode == Mode::Exclusive {
+            let changes: Vec<_> = shard_ids
+                .iter()
+                .zip(&grants)
+                .map(|(shard_id, (_, handoff))| (shard_id.as_str(), &handoff.data))
+                .collect();
+            if let Err(status) = self.record_all(&connections, &changes).await {
+                for (connection, _) in grants {
+                    connection.response_tx.restore();
+                }
+                return Err(status);
+            }
+        }
+
+        let respond_at = grants
+            .iter()
+            .map(|(_, handoff)| self.respond_at(handoff))
+            .max()
+            .unwrap_or_else(|| Instant::now() + self.latency);
+        let mut acquired = HashMap::new();
+        let mut request_rxs = Vec::new();
+        let mut response_txs = HashMap::new();
+        for (shard_id, (connection, handoff)) in shard_ids.iter().zip(grants) {
+            if handoff.unclean {
+                log::warn!("Shard {} was recovered after an unclean handoff", shard_id);
+            }
+            let known_version = known_versions.get(shard_id).cloned().unwrap_or(0);
+            acquired.insert(
+                shard_id.clone(),
+                self.acquired(&connections, shard_id, handoff, known_version),
+            );
+            request_rxs.push(connection.request_rx);
+            response_txs.insert(shard_id.clone(), connection.response_tx);
+        }
+        timer::delay(respond_at).await;
+        log::info!("Sending acquired response for shards {:?}", shard_ids);
+        let sent = response
+            .send(Ok(LockResponse {
+                body: Some(lock_response::Body::AcquiredMany(AcquiredMany {
+                    shards: acquired,
+                })),
+            }))
+            .await;
+        if sent.is_err() {
+            log::warn!(
+                "Client disconnected before receiving shards {:?}",
+                shard_ids
+            );
+            for (_, response_tx) in response_txs {
+                response_tx.restore();
+            }
+            return Ok(());
+        }
+
+        let release_requested = future::join_all(request_rxs.into_iter().map(|request_rx| {
+            let response = response.clone();
+            ConnectionReceiver::request_release(request_rx, move |shard_id| {
+                send_release(response, latency, shard_id)
+            })
+        }))
+        .fuse();
+        futures::pin_mut!(release_requested);
+
+        // Wait for the client to release every shard. The lease covers all of them, and is
+        // renewed by renewing any one.
+        let mut lease = timer::delay_for(self.lease);
+        while !response_txs.is_empty() {
+            let deadline = future::select(&mut lease, &mut release_requested);
+            let req = match future::select(request.next(), deadline).await {
+                Either::Left((req, _)) => req,
+                Either::Right((Either::Left(_), _)) => {
+                    log::warn!("Lease expired for shards {:?}", shard_ids);
+                    return Err(Status::new(
+                        Code::DeadlineExceeded,
+                        format!("lease on shards {:?} expired", shard_ids),
+                    ));
+                }
+                Either::Right((Either::Right(_), _)) => continue,
+            };
+
+            match req {
+                Some(Ok(LockRequest {
+                    body: Some(lock_request::Body::Renew(_)),
+                })) => {
+                    log::debug!("Renewing lease for shards {:?}", shard_ids);
+                    lease.reset(Instant::now() + self.lease);
+                }
+                Some(Ok(LockRequest {
+                    body: Some(lock_request::Body::ReleasedShard(released)),
+                })) => {
+                    let response_tx = match response_txs.remove(&released.shard_id) {
+                        Some(response_tx) => response_tx,
+                        None => {
+                            return Err(Status::new(
+                                Code::FailedPrecondition,
+                                format!("shard {} is not held", released.shard_id),
+                            ));
+                        }
+                    };
+                    log::info!("Received released request for shard {}", released.shard_id);
+                    let data = released.data.unwrap_or_default();
+                    self.release(&connections, &released.shard_id, mode, data, response_tx)
+                        .await?;
+                }
+                Some(Ok(_)) => {
+                    return Err(Status::new(
+                        Code::FailedPrecondition,
+                        "expected request to be `released_shard`",
+                    ));
+                }
+                _ => {
+                    return Err(Status::new(
+                        Code::DataLoss,
+                        format!("shards {:?} not released", shard_ids),
+                    ));
+                }
+            }
+        }
 
         Ok(())
     }
 }
+
+/// Finds the first of `keys` which is locked in `data`.
+fn locked_key<'a>(
+    data: &ShardData,
+    keys: impl IntoIterator<Item = &'a String>,
+) -> Option<&'a String> {
+    keys.into_iter()
+        .find(|key| data.locks.get(*key).map_or(false, KeyLock::is_locked))
+}
+
+/// Checks that none of `keys` is already in another shard, since a key in two shards could
+/// be locked through each of them at once.
+fn check_keys_free(
+    connections: &ConnectionMap,
+    shard_id: &str,
+    keys: &[String],
+) -> Result<(), Status> {
+    match connections.find_key(shard_id, keys) {
+        Some((key, other_id)) => Err(Status::new(
+            Code::AlreadyExists,
+            format!("key {} is already in shard {}", key, other_id),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Passes the responses to one of the requests in a session on to the session, tagged with
+/// the request's id.
+async fn forward_responses(
+    request_id: u64,
+    mut responses: mpsc::Receiver<Result<LockResponse, Status>>,
+    mut session: mpsc::Sender<Result<SessionResponse, Status>>,
+) {
+    while let Some(response) = responses.next().await {
+        let body = match response {
+            Ok(response) => session_response::Body::Response(response),
+            Err(status) => session_response::Body::Error(RequestError::from_status(&status)),
+        };
+        let response = SessionResponse {
+            request_id,
+            body: Some(body),
+        };
+        if session.send(Ok(response)).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Tells the client to retry its request against another server.
+async fn send_redirect(
+    response: &mut mpsc::Sender<Result<LockResponse, Status>>,
+    redirect: Redirect,
+) {
+    let _ = response
+        .send(Ok(LockResponse {
+            body: Some(lock_response::Body::Redirect(redirect)),
+        }))
+        .await;
+}
+
+/// Tells the client which shard each key now belongs to, after it asked for a shard this
+/// server doesn't have.
+async fn send_routes(
+    response: &mut mpsc::Sender<Result<LockResponse, Status>>,
+    connections: &ConnectionMap,
+) {
+    let _ = response
+        .send(Ok(LockResponse {
+            body: Some(lock_response::Body::Routes(connections.routes())),
+        }))
+        .await;
+}
+
+/// Asks the client to release a shard.
+async fn send_release(
+    mut response: mpsc::Sender<Result<LockResponse, Status>>,
+    latency: Duration,
+    shard_id: String,
+) {
+    timer::delay_for(latency).await;
+    log::info!("Sending release response for shard {}", shard_id);
+    let _ = response
+        .send(Ok(LockResponse {
+            body: Some(lock_response::Body::Release(shard_id)),
+        }))
+        .await;
+}
@@ -0,0 +1,67 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use shardik::api::StatsResponse;
+
+/// Counters describing how shards have been handed between clients since the server
+/// started.
+#[derive(Default)]
+pub struct Stats {
+    /// The number of times a shard has been granted to a client.
+    grants: AtomicU64,
+    /// The number of times a request for a holder to release a shard was held back until
+    /// the end of its minimum residency window.
+    releases_deferred: AtomicU64,
+    /// The number of acquire requests which arrived while a release was already being held
+    /// back, and so were batched into the same handoff instead of each forcing one.
+    handoffs_avoided: AtomicU64,
+    /// The number of expired key locks cleared when handing on a shard.
+    keys_expired: AtomicU64,
+    /// The number of shards split automatically because they were too busy.
+    hot_shards_split: AtomicU64,
+    /// The number of shards granted with a delta from the version the client already had.
+    deltas_sent: AtomicU64,
+    /// The number of shards granted in full even though the client had an earlier version.
+    full_resyncs: AtomicU64,
+}
+
+impl Stats {
+    pub fn grant(&self) {
+        self.grants.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn defer_release(&self) {
+        self.releases_deferred.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn avoid_handoff(&self) {
+        self.handoffs_avoided.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn expire_key(&self) {
+        self.keys_expired.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn split_hot_shard(&self) {
+        self.hot_shards_split.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn send_delta(&self) {
+        self.deltas_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn resync(&self) {
+        self.full_resyncs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn to_response(&self) -> StatsResponse {
+        StatsResponse {
+            grants: self.grants.load(Ordering::Relaxed),
+            releases_deferred: self.releases_deferred.load(Ordering::Relaxed),
+            handoffs_avoided: self.handoffs_avoided.load(Ordering::Relaxed),
+            keys_expired: self.keys_expired.load(Ordering::Relaxed),
+            hot_shards_split: self.hot_shards_split.load(Ordering::Relaxed),
+            deltas_sent: self.deltas_sent.load(Ordering::Relaxed),
+            full_resyncs: self.full_resyncs.load(Ordering::Relaxed),
+        }
+    }
+}
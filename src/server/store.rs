@@ -0,0 +1,448 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::{fs, io, thread};
+
+use bytes::Buf;
+use futures::channel::oneshot;
+use futures::{Future, FutureExt};
+use prost::Message;
+use structopt::StructOpt;
+
+use shardik::api::*;
+
+#[derive(StructOpt)]
+pub struct StoreOpts {
+    /// The directory to persist shard data in. If not set, shard data is only kept in memory.
+    /// In a cluster, this is where the server keeps its replicated log.
+    #[structopt(long, parse(from_os_str))]
+    data_dir: Option<PathBuf>,
+    /// The number of log entries to write before compacting the log into a snapshot.
+    #[structopt(long, default_value = "1024")]
+    snapshot_interval: usize,
+}
+
+/// Persists the data for each shard so it can be recovered when the server restarts.
+#[tonic::async_trait]
+pub trait Store: Send + Sync {
+    /// Loads the last recorded data for each shard.
+    fn load(&self) -> io::Result<HashMap<String, ShardData>>;
+    /// Records the latest data for the shards in an entry, all at once.
+    async fn record(&self, entry: StoreEntry) -> io::Result<()>;
+}
+
+/// Makes an entry recording the latest data for each of the given shards.
+pub fn entry(changes: &[(&str, &ShardData)]) -> StoreEntry {
+    let (first, more) = changes.split_first().expect("no shards to record");
+    StoreEntry {
+        shard_id: first.0.to_owned(),
+        data: Some(first.1.clone()),
+        more_shards: more
+            .iter()
+            .map(|(shard_id, data)| ((*shard_id).to_owned(), (*data).clone()))
+            .collect(),
+    }
+}
+
+/// Updates the data for each shard recorded in an entry.
+pub fn apply(shards: &mut HashMap<String, ShardData>, entry: StoreEntry) {
+    shards.insert(entry.shard_id, entry.data.unwrap_or_default());
+    shards.extend(entry.more_shards);
+}
+
+pub fn open(opts: StoreOpts) -> io::Result<Arc<dyn Store>> {
+    match opts.data_dir {
+        Some(dir) => Ok(Arc::new(DiskStore::open(dir, opts.snapshot_interval)?)),
+        None => Ok(Arc::new(MemoryStore)),
+    }
+}
+
+/// A store which doesn't persist anything.
+pub struct MemoryStore;
+
+#[tonic::async_trait]
+impl Store for MemoryStore {
+    fn load(&self) -> io::Result<HashMap<String, ShardData>> {
+        Ok(HashMap::new())
+    }
+
+    async fn record(&self, _: StoreEntry) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A store which appends every change to a write-ahead log on disk, and periodically
+/// compacts the log into a snapshot. The writing is done on a thread of its own, so that
+/// syncing to disk doesn't hold up other requests.
+pub struct DiskStore {
+    shards: Arc<Mutex<HashMap<String, ShardData>>>,
+    /// Behind a mutex only so that the store can be shared between threads.
+    entries_tx: Mutex<mpsc::Sender<DiskBatch>>,
+}
+
+/// An entry to write, and where to send the result once it's on disk.
+type DiskBatch = (StoreEntry, oneshot::Sender<io::Result<()>>);
+
+struct DiskWriter {
+    dir: PathBuf,
+    snapshot_interval: usize,
+    log: fs::File,
+    log_len: usize,
+    /// The length of the log in bytes, up to the end of the last entry written in full.
+    log_bytes: u64,
+    shards: Arc<Mutex<HashMap<String, ShardData>>>,
+}
+
+const SNAPSHOT_FILE: &str = "snapshot.bin";
+const LOG_FILE: &str = "log.bin";
+
+impl DiskStore {
+    pub fn open(dir: PathBuf, snapshot_interval: usize) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+
+        let mut shards = match read_file(&dir.join(SNAPSHOT_FILE))? {
+            Some(buf) => Snapshot::decode(buf).map_err(invalid_data)?.shards,
+            None => HashMap::new(),
+        };
+        let mut log_len = 0;
+        if let Some(buf) = read_file(&dir.join(LOG_FILE))? {
+            let mut buf = io::Cursor::new(buf);
+            while buf.has_remaining() {
+                match StoreEntry::decode_length_delimited(&mut buf) {
+                    Ok(entry) => {
+                        apply(&mut shards, entry);
+                        log_len += 1;
+                    }
+                    Err(err) => {
+                        // The server probably crashed part way through writing this entry.
+                        log::warn!("Ignoring truncated log entry: {}", err);
+                        break;
+                    }
+                }
+            }
+        }
+        log::info!(
+            "Loaded {} shards from {} ({} log entries)",
+            shards.len(),
+            dir.display(),
+            log_len
+        );
+
+        let log = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(LOG_FILE))?;
+        let shards = Arc::new(Mutex::new(shards));
+        let mut writer = DiskWriter {
+            dir,
+            snapshot_interval,
+            log,
+            log_len,
+            log_bytes: 0,
+            shards: shards.clone(),
+        };
+        // Start from a clean log, so that any truncated entry is not appended to.
+        writer.snapshot()?;
+
+        let (entries_tx, entries_rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("disk-store".to_owned())
+            .spawn(move || writer.run(entries_rx))?;
+        Ok(DiskStore {
+            shards,
+            entries_tx: Mutex::new(entries_tx),
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl Store for DiskStore {
+    fn load(&self) -> io::Result<HashMap<String, ShardData>> {
+        Ok(self.shards.lock().unwrap().clone())
+    }
+
+    async fn record(&self, entry: StoreEntry) -> io::Result<()> {
+        let (done_tx, done_rx) = oneshot::channel();
+        // If the writer has stopped, `done_tx` is dropped and the write fails.
+        let _ = self.entries_tx.lock().unwrap().send((entry, done_tx));
+        done_rx.await.unwrap_or_else(|_| Err(stopped()))
+    }
+}
+
+impl DiskWriter {
+    fn run(mut self, entries_rx: mpsc::Receiver<DiskBatch>) {
+        for (entry, done_tx) in entries_rx {
+            let result = self.record(entry);
+            let failed = result.is_err();
+            let _ = done_tx.send(result);
+            if failed {
+                if let Err(err) = self.discard_partial() {
+                    // Any later entries would be lost behind the partial one, so fail them all.
+                    log::error!("Stopped writing to the store: {}", err);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Cuts off anything written of an entry which failed part way through. Reading the log
+    /// back stops at the first partial entry, so any entries after it would be lost.
+    fn discard_partial(&mut self) -> io::Result<()> {
+        self.log.set_len(self.log_bytes)?;
+        self.log.sync_all()
+    }
+
+    fn record(&mut self, entry: StoreEntry) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(entry.encoded_len() + 10);
+        entry
+            .encode_length_delimited(&mut buf)
+            .map_err(invalid_data)?;
+
+        self.log.write_all(&buf)?;
+        self.log.sync_data()?;
+        self.log_len += 1;
+        self.log_bytes += buf.len() as u64;
+        apply(&mut self.shards.lock().unwrap(), entry);
+
+        if self.log_len >= self.snapshot_interval {
+            self.snapshot()?;
+        }
+        Ok(())
+    }
+
+    /// Writes all shard data to a new snapshot file and truncates the log.
+    fn snapshot(&mut self) -> io::Result<()> {
+        let snapshot = Snapshot {
+            shards: self.shards.lock().unwrap().clone(),
+        };
+        let mut buf = Vec::with_capacity(snapshot.encoded_len());
+        snapshot.encode(&mut buf).map_err(invalid_data)?;
+
+        replace_file(&self.dir, SNAPSHOT_FILE, &buf)?;
+
+        self.log.set_len(0)?;
+        self.log.sync_all()?;
+        self.log_len = 0;
+        self.log_bytes = 0;
+        log::debug!("Wrote snapshot of {} shards", snapshot.shards.len());
+        Ok(())
+    }
+}
+
+/// The state of a server in a cluster, as loaded from disk.
+#[derive(Default)]
+pub struct RaftLog {
+    pub state: RaftState,
+    pub snapshot: RaftSnapshot,
+    /// The entries following the snapshot.
+    pub entries: Vec<LogEntry>,
+}
+
+/// A change to the state of a server in a cluster.
+pub enum RaftWrite {
+    /// Replaces the term and vote.
+    State(RaftState),
+    /// Adds entries to the end of the log.
+    Append(Vec<RaftLogEntry>),
+    /// Replaces the whole log, along with the snapshot it follows if that has changed.
+    Replace(Option<RaftSnapshot>, Vec<RaftLogEntry>),
+}
+
+/// Persists the term, vote and log of a server in a cluster, so that it keeps the promises it
+/// made to its peers when it restarts. Writes are made in order on a thread of their own.
+pub struct RaftStore {
+    /// Not set if the state is only kept in memory.
+    writes_tx: Option<mpsc::Sender<RaftBatch>>,
+}
+
+/// Writes to make together, and where to send the result once they're on disk.
+type RaftBatch = (Vec<RaftWrite>, oneshot::Sender<io::Result<()>>);
+
+struct RaftWriter {
+    dir: PathBuf,
+    log: fs::File,
+}
+
+const RAFT_STATE_FILE: &str = "raft_state.bin";
+const RAFT_SNAPSHOT_FILE: &str = "raft_snapshot.bin";
+const RAFT_LOG_FILE: &str = "raft_log.bin";
+
+pub fn open_raft(opts: &StoreOpts) -> io::Result<(RaftStore, RaftLog)> {
+    let dir = match &opts.data_dir {
+        Some(dir) => dir.clone(),
+        None => return Ok((RaftStore { writes_tx: None }, RaftLog::default())),
+    };
+    fs::create_dir_all(&dir)?;
+
+    let state = match read_file(&dir.join(RAFT_STATE_FILE))? {
+        Some(buf) => RaftState::decode(buf).map_err(invalid_data)?,
+        None => RaftState::default(),
+    };
+    let snapshot = match read_file(&dir.join(RAFT_SNAPSHOT_FILE))? {
+        Some(buf) => RaftSnapshot::decode(buf).map_err(invalid_data)?,
+        None => RaftSnapshot::default(),
+    };
+    let mut logged = Vec::new();
+    if let Some(buf) = read_file(&dir.join(RAFT_LOG_FILE))? {
+        let mut buf = io::Cursor::new(buf);
+        while buf.has_remaining() {
+            let entry = match RaftLogEntry::decode_length_delimited(&mut buf) {
+                Ok(entry) => entry,
+                Err(err) => {
+                    // The server probably crashed part way through writing this entry.
+                    log::warn!("Ignoring truncated log entry: {}", err);
+                    break;
+                }
+            };
+            // The old log is left behind if the server crashed between writing a snapshot and
+            // replacing the log.
+            if entry.index <= snapshot.index {
+                continue;
+            }
+            if entry.index != snapshot.index + logged.len() as u64 + 1 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("log entries are missing before index {}", entry.index),
+                ));
+            }
+            logged.push(entry);
+        }
+    }
+    log::info!(
+        "Loaded term {} from {} ({} log entries after index {})",
+        state.term,
+        dir.display(),
+        logged.len(),
+        snapshot.index
+    );
+
+    let log = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(RAFT_LOG_FILE))?;
+    let mut writer = RaftWriter { dir, log };
+    // Start from a clean log, so that any truncated entry is not appended to.
+    writer.replace_log(&logged)?;
+
+    let (writes_tx, writes_rx) = mpsc::channel();
+    thread::Builder::new()
+        .name("raft-store".to_owned())
+        .spawn(move || writer.run(writes_rx))?;
+
+    let entries = logged
+        .into_iter()
+        .map(|entry| entry.entry.unwrap_or_default())
+        .collect();
+    Ok((
+        RaftStore {
+            writes_tx: Some(writes_tx),
+        },
+        RaftLog {
+            state,
+            snapshot,
+            entries,
+        },
+    ))
+}
+
+impl RaftStore {
+    /// Queues writes to be made after any earlier ones. They are queued straight away, and the
+    /// returned future completes once they have been synced to disk.
+    pub fn write(&self, writes: Vec<RaftWrite>) -> impl Future<Output = io::Result<()>> {
+        let (done_tx, done_rx) = oneshot::channel();
+        match &self.writes_tx {
+            // If the writer has stopped, `done_tx` is dropped and the write fails.
+            Some(writes_tx) => {
+                let _ = writes_tx.send((writes, done_tx));
+            }
+            None => {
+                let _ = done_tx.send(Ok(()));
+            }
+        }
+        done_rx.map(|result| result.unwrap_or_else(|_| Err(stopped())))
+    }
+}
+
+impl RaftWriter {
+    fn run(mut self, writes_rx: mpsc::Receiver<RaftBatch>) {
+        for (writes, done_tx) in writes_rx {
+            let result = writes.into_iter().try_for_each(|write| self.write(write));
+            let failed = result.is_err();
+            let _ = done_tx.send(result);
+            if failed {
+                // Any later writes could leave a gap in the log, so fail them all.
+                return;
+            }
+        }
+    }
+
+    fn write(&mut self, write: RaftWrite) -> io::Result<()> {
+        match write {
+            RaftWrite::State(state) => replace_file(&self.dir, RAFT_STATE_FILE, &encode(&state)?),
+            RaftWrite::Append(entries) => {
+                self.log.write_all(&encode_entries(&entries)?)?;
+                self.log.sync_data()
+            }
+            RaftWrite::Replace(snapshot, entries) => {
+                if let Some(snapshot) = snapshot {
+                    replace_file(&self.dir, RAFT_SNAPSHOT_FILE, &encode(&snapshot)?)?;
+                }
+                self.replace_log(&entries)
+            }
+        }
+    }
+
+    fn replace_log(&mut self, entries: &[RaftLogEntry]) -> io::Result<()> {
+        replace_file(&self.dir, RAFT_LOG_FILE, &encode_entries(entries)?)?;
+        self.log = fs::OpenOptions::new()
+            .append(true)
+            .open(self.dir.join(RAFT_LOG_FILE))?;
+        Ok(())
+    }
+}
+
+fn encode(message: &impl Message) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(message.encoded_len());
+    message.encode(&mut buf).map_err(invalid_data)?;
+    Ok(buf)
+}
+
+fn encode_entries(entries: &[RaftLogEntry]) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    for entry in entries {
+        entry
+            .encode_length_delimited(&mut buf)
+            .map_err(invalid_data)?;
+    }
+    Ok(buf)
+}
+
+/// Replaces the contents of a file, so that it has either the old or the new contents if the
+/// server crashes part way through.
+fn replace_file(dir: &Path, name: &str, buf: &[u8]) -> io::Result<()> {
+    let tmp_path = dir.join(format!("{}.tmp", name));
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(buf)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, dir.join(name))
+}
+
+fn read_file(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    let mut file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+    Ok(Some(buf))
+}
+
+fn invalid_data(err: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+fn stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "store stopped")
+}
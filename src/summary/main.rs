@@ -1,3 +1,5 @@
+use std::collections::BTreeMap;
+
 use structopt::StructOpt;
 
 use shardik::metrics::{self, MetricsOpts, Record};
@@ -23,6 +25,40 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("mean:\t{:.0} ns", stats::mean(nanos(&records)));
     println!("median:\t{:.0} ns", stats::median(nanos(&records)).unwrap());
 
+    let mut by_priority = BTreeMap::<u32, Vec<f64>>::new();
+    for record in &records {
+        by_priority
+            .entry(record.priority)
+            .or_default()
+            .push(record.nanos as f64);
+    }
+    if by_priority.len() > 1 {
+        for (priority, nanos) in by_priority {
+            println!(
+                "priority {}:\t{} entries, mean {:.0} ns, median {:.0} ns",
+                priority,
+                nanos.len(),
+                stats::mean(nanos.iter().cloned()),
+                stats::median(nanos.iter().cloned()).unwrap()
+            );
+        }
+    }
+
+    let key_waits: Vec<f64> = records
+        .iter()
+        .filter(|record| record.key_wait_nanos > 0)
+        .map(|record| record.key_wait_nanos as f64)
+        .collect();
+    if !key_waits.is_empty() {
+        println!(
+            "waited for key:\t{} entries, mean {:.0} ns, median {:.0} ns, max {:.0} ns",
+            key_waits.len(),
+            stats::mean(key_waits.iter().cloned()),
+            stats::median(key_waits.iter().cloned()).unwrap(),
+            key_waits.iter().cloned().fold(0.0, f64::max)
+        );
+    }
+
     Ok(())
 }
 